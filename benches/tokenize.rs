@@ -0,0 +1,57 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sketch_parser::parser::{tokenize, Parser};
+
+// A chart with roughly `lines` lines. Every group of states is a compound
+// state with a few children, transitions between them and some comments, so
+// the benchmark goes through all the kinds of tokens we have.
+fn generate_chart(lines: usize) -> String {
+    let mut chart = String::from("machine\n");
+    let mut group = 0;
+
+    // every group is 8 lines
+    while group * 8 < lines {
+        chart.push_str(&format!("  group{}*\n", group));
+        chart.push_str("    % waiting for the user\n");
+        chart.push_str(&format!("    idle{}*\n", group));
+        chart.push_str(&format!("      click -> busy{}; isEnabled > track > log\n", group));
+        chart.push_str(&format!("    busy{}\n", group));
+        chart.push_str(&format!("      done -> #machine.group{}.idle{}\n", group, group));
+        chart.push_str("      fail -> größe %kaputt\n");
+        chart.push_str("    größe$\n");
+        group += 1;
+    }
+
+    chart
+}
+
+fn bench_tokenize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tokenize");
+
+    // the time per byte should stay the same as the charts get bigger
+    for &lines in &[10_000, 25_000, 50_000, 100_000] {
+        let chart = generate_chart(lines);
+        group.throughput(Throughput::Bytes(chart.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(lines), &chart, |b, chart| {
+            b.iter(|| tokenize(chart))
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+
+    for &lines in &[10_000, 25_000, 50_000, 100_000] {
+        let chart = generate_chart(lines);
+        group.throughput(Throughput::Bytes(chart.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(lines), &chart, |b, chart| {
+            b.iter(|| Parser::new().parse(chart).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_tokenize, bench_parse);
+criterion_main!(benches);
@@ -1 +1,3 @@
 pub mod parser;
+pub mod resolve;
+pub mod xstate;
@@ -1,30 +1,183 @@
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use indexmap::IndexMap;
 
+mod error;
+mod options;
 mod tokenizer;
-use tokenizer::*;
+pub use error::ParseError;
+pub use options::{Indentation, ParserOptions};
+pub use tokenizer::{tokenize, Lexer, Position, Span, Token, TokenKind, TokenType};
+use tokenizer::BOM;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
-enum StateType {
+pub enum StateType<'a> {
     AtomicState,
     CompoundState,
     FinalState,
     ParallelState,
+    // `name H` or `name H*`. A history state remembers which child of its
+    // parent was active when the parent was left, and going to it goes back
+    // there. target is where to go when there's nothing to remember yet,
+    // e.g. `name H -> step1`.
+    History {
+        mode: HistoryMode,
+        target: Option<&'a str>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HistoryMode {
+    // remembers only the child of the parent, which starts from its own
+    // initial state
+    Shallow,
+    // remembers all the way down
+    Deep,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct TransitionNode<'a> {
-    event: &'a str,
-    target: &'a str,
-    cond: Option<&'a str>,
-    actions: Option<Vec<&'a str>>,
+    pub event: &'a str,
+    // empty for transitions which only run actions, e.g. `click > increment`
+    pub target: &'a str,
+    // the guard as it's written, e.g. `isValid and not isLocked`
+    pub cond: Option<&'a str>,
+    pub guard: Option<Guard<'a>>,
+    pub actions: Option<Vec<Call<'a>>>,
+    // in milliseconds, for `after 3000ms -> target`. Delayed transitions
+    // don't have an event.
+    pub delay: Option<u64>,
+    // `->>` instead of `->`. External transitions leave the state they start
+    // in and enter it again, even when they go to the state itself or to one
+    // of its children, so its exit and entry actions run and its children
+    // start over from their initial states. Internal transitions don't.
+    pub internal: bool,
+    pub spans: TransitionSpans,
+}
+
+// `log("entered", level=2)`, or just `log`. Actions and the guards in a
+// guard expression are both written like this.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Call<'a> {
+    pub name: &'a str,
+    pub args: Vec<Argument<'a>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Argument<'a> {
+    // None for positional arguments
+    pub name: Option<&'a str>,
+    pub value: Literal<'a>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Literal<'a> {
+    // `"entered"`, or a bare word like the `admin` in `hasRole(admin)`
+    String(&'a str),
+    Number(f64),
+    Bool(bool),
+}
+
+// Numbers are only ever parsed from digits, so they are never NaN. That makes
+// them equal to themselves, which is all Eq asks for.
+impl<'a> Eq for Literal<'a> {}
+
+// A call without arguments
+impl<'a> From<&'a str> for Call<'a> {
+    fn from(name: &'a str) -> Self {
+        Call { name, args: vec![] }
+    }
+}
+
+impl<'a> fmt::Display for Call<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        if !self.args.is_empty() {
+            let args: Vec<String> = self.args.iter().map(|arg| arg.to_string()).collect();
+            write!(f, "({})", args.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for Argument<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.name {
+            write!(f, "{}=", name)?;
+        }
+
+        match self.value {
+            Literal::String(text) => write!(f, "\"{}\"", text),
+            Literal::Number(number) => write!(f, "{}", number),
+            Literal::Bool(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// `isValid and not (isLocked or isBusy)`. Parentheses are gone once it's
+// parsed, the nesting says what goes with what.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Guard<'a> {
+    Named(Call<'a>),
+    Not(Box<Guard<'a>>),
+    And(Vec<Guard<'a>>),
+    Or(Vec<Guard<'a>>),
+}
+
+// Writes the guard back out, with parentheses only where they are needed.
+// `not` binds tighter than `and`, which binds tighter than `or`.
+impl<'a> fmt::Display for Guard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Guard::Named(name) => write!(f, "{}", name),
+            Guard::Not(guard) => match **guard {
+                Guard::Named(_) | Guard::Not(_) => write!(f, "not {}", guard),
+                _ => write!(f, "not ({})", guard),
+            },
+            Guard::And(guards) => {
+                let guards: Vec<String> = guards
+                    .iter()
+                    .map(|guard| match guard {
+                        Guard::Or(_) => format!("({})", guard),
+                        _ => guard.to_string(),
+                    })
+                    .collect();
+                write!(f, "{}", guards.join(" and "))
+            }
+            Guard::Or(guards) => {
+                let guards: Vec<String> = guards.iter().map(|guard| guard.to_string()).collect();
+                write!(f, "{}", guards.join(" or "))
+            }
+        }
+    }
+}
+
+// Where each part of a transition is in the input. Exporters, linters and
+// editors use these to point back into the source.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct TransitionSpans {
+    // the whole transition, from the event to the last action
+    pub transition: Span,
+    // None for transient and delayed transitions
+    pub event: Option<Span>,
+    // the `3000ms` of a delayed transition
+    pub delay: Option<Span>,
+    // None for targetless transitions
+    pub target: Option<Span>,
+    // just the guard and the names of the actions, without the `;` and `>`
+    pub cond: Option<Span>,
+    pub actions: Vec<Span>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct StateNode<'a> {
-    id: &'a str,
-    typ: StateType,
-    initial: Option<&'a str>,
-    is_initial: bool,
+    pub id: &'a str,
+    pub typ: StateType<'a>,
+    pub initial: Option<&'a str>,
+    pub is_initial: bool,
     // xstate has a representation of events as
     // {
         // on: [
@@ -39,14 +192,82 @@ pub struct StateNode<'a> {
     // We can anyways convert the final json to various forms. E.g. we can 
     // convert most events to { on: { 'click': 'go_to_state_1' }} form, because
     // that's what most people want. Or not.
-    on: Vec<TransitionNode<'a>>,
-    states: HashMap<&'a str, StateNode<'a>>,
+    pub on: Vec<TransitionNode<'a>>,
+    // actions to run when the state is entered and left. From the
+    // `entry > action` and `exit > action` lines in the state's block.
+    pub entry: Vec<Call<'a>>,
+    pub exit: Vec<Call<'a>>,
+    // Long running things which run for as long as the state is active.
+    // Activities only have a name, invoked services can also be done or
+    // fail, and transitions can be taken when they do.
+    pub activities: Vec<Call<'a>>,
+    pub invoke: Vec<InvokeNode<'a>>,
+    // Sub states in the order they are written in. A HashMap would shuffle
+    // them on every run, and then so would everything exported from here.
+    pub states: IndexMap<&'a str, StateNode<'a>>,
+    pub spans: StateSpans,
 }
 
+impl<'a> StateNode<'a> {
+    // The name the parent state knows this state by. A state written as
+    // `#name` is `name` to its parent, and `name` is its explicit id too.
+    pub fn key(&self) -> &'a str {
+        self.id.strip_prefix('#').unwrap_or(self.id)
+    }
+
+    pub fn explicit_id(&self) -> Option<&'a str> {
+        self.id.strip_prefix('#')
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct StateSpans {
+    // the whole state, including the block indented under it
+    pub state: Span,
+    pub id: Span,
+    // the `&`, `$` and `*` after the id
+    pub parallel_marker: Option<Span>,
+    pub final_marker: Option<Span>,
+    pub initial_marker: Option<Span>,
+    // the `H` or `H*`, and the default target after it
+    pub history_marker: Option<Span>,
+    pub history_target: Option<Span>,
+    // the names of the entry and exit actions, and of the activities
+    pub entry: Vec<Span>,
+    pub exit: Vec<Span>,
+    pub activities: Vec<Span>,
+}
+
+// invoke fetchUser
+//   done -> success > saveUser
+//   error -> failure
 #[derive(Debug, PartialEq, Eq, Clone)]
-enum TransitionOrState<'a> {
-    State(StateNode<'a>),
-    Transition(TransitionNode<'a>)
+pub struct InvokeNode<'a> {
+    // the service to invoke
+    pub src: &'a str,
+    pub on_done: Vec<TransitionNode<'a>>,
+    pub on_error: Vec<TransitionNode<'a>>,
+    pub spans: InvokeSpans,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct InvokeSpans {
+    // the whole invoke, including the block indented under it
+    pub invoke: Span,
+    pub src: Span,
+}
+
+// The things which can be in the indented block under a state
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum BlockItem<'a> {
+    // boxed because a state is a lot bigger than everything else in here
+    State(Box<StateNode<'a>>),
+    Transition(TransitionNode<'a>),
+    // the action names and their spans
+    Entry(Vec<Call<'a>>, Vec<Span>),
+    Exit(Vec<Call<'a>>, Vec<Span>),
+    Activity(Vec<Call<'a>>, Vec<Span>),
+    Invoke(InvokeNode<'a>),
 }
 // TODO: This return value is not enough. We need to consume the token, which
 // means updating the offset. Each parser can change the offset by different
@@ -111,9 +332,54 @@ enum TransitionOrState<'a> {
 // the offset/index back to some previous position.
 
 pub struct Parser<'a> {
-    tokens: Vec<Token<'a>>,
+    options: ParserOptions,
+    // The parser asks the lexer for tokens only when it gets to them, so a
+    // chart which fails on line 3 is only tokenized up to line 3. The tokens
+    // seen so far are kept, because backtracking needs to look at them again.
+    // Once a line of a block is parsed the parser never goes back before it,
+    // so the tokens before it are dropped, see forget_before. That way a
+    // big chart is never all in memory as tokens at once.
+    // RefCell for the same reason as furthest_failure below.
+    lexer: RefCell<Lexer<'a>>,
+    tokens: RefCell<VecDeque<Token<'a>>>,
+    // how many tokens have been dropped from the front of tokens, which is
+    // the offset of the first one still there
+    forgotten: Cell<usize>,
+    // The furthest token offset any parser failed at and the kinds of tokens
+    // that would have been accepted there. Backtracking means most failures
+    // are not errors, but the furthest one is the best guess at where the
+    // input actually goes wrong.
+    // RefCell because all the token parsers only borrow self immutably.
+    furthest_failure: RefCell<Failure>,
+    input_str: &'a str,
+    // When recovering, a line that doesn't parse is recorded in errors and
+    // skipped instead of ending the block it is in.
+    recover: bool,
+    errors: Vec<ParseError<'a>>,
+    // Built the first time an error needs a position which isn't a token's,
+    // so every error after that only has to look at its own line.
+    line_starts: RefCell<Vec<usize>>,
+    // how many states deep state_parser currently is
+    depth: usize,
+    // how many `not`s and `(`s deep guard_operand currently is. Cell because
+    // the guard parsers only borrow self immutably too.
+    guard_depth: Cell<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Failure {
+    offset: usize,
+    expected: Vec<TokenKind>,
+    // Set when the parser gave up for some other reason than the wrong kind
+    // of token, e.g. a guard nested too deep. This is the error message then.
+    message: Option<String>,
 }
 
+// Every nested state is another recursive call to state_parser. Past this
+// many levels we report an error instead of running out of stack. Same goes
+// for `not` and `(` in guards, which are recursive calls to guard_operand.
+const MAX_DEPTH: usize = 100;
+
 // looks like i can't write this method zero_or_one in rust
 // It needs a mutable reference to it's self type. But the function it takes
 // which parses the current token also needs mutable reference to self. That
@@ -153,14 +419,23 @@ where
         parsed_values.push(v);
     }
 
-    if parsed_values.len() > 0 {
-        return (new_offset, Some(parsed_values));
+    if !parsed_values.is_empty() {
+        (new_offset, Some(parsed_values))
     } else {
-        return (offset, None);
+        (offset, None)
     }
 }
 
-fn get_state_type(is_parallel_state: bool, is_final_state: bool, sub_states_count: usize) -> StateType {
+fn get_state_type<'a>(
+    is_parallel_state: bool,
+    is_final_state: bool,
+    history: Option<(HistoryMode, Option<&'a str>)>,
+    sub_states_count: usize,
+) -> StateType<'a> {
+    if let Some((mode, target)) = history {
+        return StateType::History { mode, target };
+    }
+
     if is_parallel_state {
         return StateType::ParallelState;
     }
@@ -174,25 +449,78 @@ fn get_state_type(is_parallel_state: bool, is_final_state: bool, sub_states_coun
     }
 
 
-    return StateType::AtomicState;
+    StateType::AtomicState
 }
 
-fn get_initial_state<'a>(sub_states: &Vec<(&'a str, StateNode<'a>)>) -> Option<&'a str> {
-    if sub_states.len() == 0 {
-        return None;
+fn get_initial_state<'a>(sub_states: &[(&'a str, StateNode<'a>)]) -> Option<&'a str> {
+    if let Some((initial_sub_state, _)) = sub_states.iter().find(|(_, s)| s.is_initial) {
+        Some(initial_sub_state)
+    } else {
+        // A history state only points somewhere else, it's not a state the
+        // parent can start in. Without a target it even goes to the parent's
+        // initial state, which would be itself.
+        sub_states
+            .iter()
+            .find(|(_, s)| !matches!(s.typ, StateType::History { .. }))
+            .map(|(initial_sub_state, _)| *initial_sub_state)
     }
+}
 
-    if let Some((initial_sub_state, _)) = sub_states.iter().find(|(_, s)| s.is_initial == true) {
-        return Some(initial_sub_state);
+// `3000ms` -> 3000, `5s` -> 5000, `1.5s` -> 1500
+fn parse_delay(text: &str) -> Option<u64> {
+    let (number, factor) = if let Some(number) = text.strip_suffix("ms") {
+        (number, 1.0)
+    } else if let Some(number) = text.strip_suffix('s') {
+        (number, 1000.0)
     } else {
-        let (initial_sub_state, _) = sub_states[0];
-        return Some(initial_sub_state);
+        return None;
+    };
+
+    // f64 also parses things like `inf` and `1e3`, which aren't delays
+    if !number.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
     }
+
+    let number: f64 = number.parse().ok()?;
+    let delay = (number * factor).round();
+
+    // rust tip: `as` doesn't fail when the number doesn't fit. It gives the
+    // closest number which does, so a huge delay would quietly become
+    // u64::MAX. u64::MAX as f64 rounds up to 2^64, which doesn't fit either.
+    if delay >= u64::MAX as f64 {
+        return None;
+    }
+
+    Some(delay as u64)
+}
+
+// Where every line in the input starts. Lines end the same way they do in the
+// lexer, with \n, \r\n or \r.
+fn find_line_starts(input_str: &str) -> Vec<usize> {
+    let bytes = input_str.as_bytes();
+    let mut starts = vec![0];
+
+    for (i, byte) in bytes.iter().enumerate() {
+        match byte {
+            b'\n' => starts.push(i + 1),
+            // a \r followed by a \n is counted with the \n
+            b'\r' if bytes.get(i + 1) != Some(&b'\n') => starts.push(i + 1),
+            _ => {}
+        }
+    }
+
+    starts
 }
 
 // all parsers return Option<(offset, returnValueForThatParser)>
 // all parser combinators return (offset, Option<returnValueForParser or Vec<returnValueForParser>>)
 
+impl<'a> Default for Parser<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> Parser<'a> {
     // Question: Should the input str be sent when creating a new parser or
     // during the call to parse? If we send it during Parser creation, we have
@@ -209,26 +537,256 @@ impl<'a> Parser<'a> {
     // 1. Store the input_str inside the parser
     // 2. Won't have to create a new instance of Parser for every new parse
     pub fn new() -> Parser<'a> {
-        Parser { tokens: vec![] }
+        Parser::with_options(ParserOptions::default())
     }
 
-    fn get_token_at(&self, offset: usize) -> Option<&Token<'a>> {
-        if offset < self.tokens.len() {
-            return Some(&self.tokens[offset]);
+    pub fn with_options(options: ParserOptions) -> Parser<'a> {
+        Parser {
+            options,
+            lexer: RefCell::new(Lexer::new("")),
+            tokens: RefCell::new(VecDeque::new()),
+            forgotten: Cell::new(0),
+            furthest_failure: RefCell::new(Failure::default()),
+            input_str: "",
+            recover: false,
+            errors: vec![],
+            line_starts: RefCell::new(vec![]),
+            depth: 0,
+            guard_depth: Cell::new(0),
         }
+    }
 
-        None
+    // Every token parser calls this when the token at offset is not what it
+    // wants.
+    fn expected(&self, offset: usize, kind: TokenKind) {
+        let mut furthest_failure = self.furthest_failure.borrow_mut();
+
+        if offset > furthest_failure.offset {
+            *furthest_failure = Failure {
+                offset,
+                expected: vec![kind],
+                message: None,
+            };
+        } else if offset == furthest_failure.offset && !furthest_failure.expected.contains(&kind) {
+            furthest_failure.expected.push(kind);
+        }
+    }
+
+    // Like expected, for when no kind of token would have done at offset
+    fn failed_with(&self, offset: usize, message: String) {
+        let mut furthest_failure = self.furthest_failure.borrow_mut();
+
+        if offset >= furthest_failure.offset {
+            *furthest_failure = Failure {
+                offset,
+                expected: vec![],
+                message: Some(message),
+            };
+        }
     }
 
-    // This parser works for all parsers which want to compare token to 
+    // The line and column of a byte offset in the input. input_str.len() is
+    // where the parser ends up when it runs out of tokens, just past the last
+    // character.
+    fn position_at(&self, offset: usize) -> Position {
+        let mut line_starts = self.line_starts.borrow_mut();
+        if line_starts.is_empty() {
+            *line_starts = find_line_starts(self.input_str);
+        }
+
+        // the last line starting at or before offset
+        let line_number = line_starts.partition_point(|start| *start <= offset) - 1;
+        let line = &self.input_str[line_starts[line_number]..offset];
+        // the byte order mark isn't something editors show
+        let line = if line_number == 0 { line.trim_start_matches(BOM) } else { line };
+
+        Position {
+            line_number,
+            col: line.chars().count(),
+            offset,
+        }
+    }
+
+    fn trailing_tokens_error(&self, offset: usize) -> ParseError<'a> {
+        // If some parser got further than where the root state ended, that's
+        // where the real problem is. E.g. a transition missing its target.
+        if self.furthest_failure.borrow().offset >= offset {
+            return self.error_at_furthest_failure();
+        }
+
+        let token = self.token_at(offset);
+        let mut error = ParseError::unexpected(token.pos, Some(token.typ), vec![]);
+        error.message = format!("{} after the end of the root state", error.message);
+
+        error
+    }
+
+    fn error_at_furthest_failure(&self) -> ParseError<'a> {
+        let Failure { offset, expected, message } = self.furthest_failure.borrow().clone();
+
+        let mut error = match self.get_token_at(offset) {
+            Some(token) => ParseError::unexpected(token.pos, Some(token.typ), expected),
+            // ran out of tokens
+            None => ParseError::unexpected(self.position_at(self.input_str.len()), None, expected),
+        };
+        if let Some(message) = message {
+            error.message = message;
+        }
+
+        error
+    }
+
+    // Used when recovering. Records the error for the line starting at offset
+    // and returns the offset where parsing can pick up again.
+    fn recover_from(&mut self, offset: usize) -> usize {
+        let error = if self.furthest_failure.borrow().offset >= offset {
+            self.error_at_furthest_failure()
+        } else {
+            let token = self.token_at(offset);
+            ParseError::unexpected(token.pos, Some(token.typ), vec![])
+        };
+        self.errors.push(error);
+
+        let new_offset = self.skip_line(offset);
+        // failures before this point have been reported. Start afresh.
+        self.furthest_failure = RefCell::new(Failure {
+            offset: new_offset,
+            ..Default::default()
+        });
+
+        new_offset
+    }
+
+    // Skips the rest of the line the token at offset is on, along with any
+    // block indented under that line. Stops at the start of the next line at
+    // the same level, or at the dedent which closes the current block.
+    fn skip_line(&self, offset: usize) -> usize {
+        let line_number = self.token_at(offset).pos.line_number;
+        let mut depth = 0;
+        let mut new_offset = offset;
+
+        while let Some(token) = self.get_token_at(new_offset) {
+            match token.typ {
+                TokenType::Indent => depth += 1,
+                // always skip at least one token, or we'd never get anywhere
+                TokenType::Dedent if new_offset == offset => {}
+                TokenType::Dedent if depth == 0 => break,
+                TokenType::Dedent => depth -= 1,
+                _ if new_offset > offset && depth == 0 && token.pos.line_number > line_number => break,
+                _ => {}
+            }
+
+            new_offset += 1;
+        }
+
+        new_offset
+    }
+
+    // Span of the token at offset. Only for tokens which have been parsed, so
+    // we know they are there.
+    fn span_at(&self, offset: usize) -> Span {
+        self.token_at(offset).span
+    }
+
+    // The span of the text the parsed tokens from start to end (exclusive)
+    // cover
+    fn span_between(&self, start: usize, end: usize) -> Span {
+        Span {
+            start: self.token_at(start).span.start,
+            end: self.end_before(start, end),
+        }
+    }
+
+    // Where the last token before end ends. Dedents at the end are left out.
+    // They sit at the start of the next line, which is not part of what was
+    // parsed. forget_before keeps that token around, so this works even when
+    // the token at start is gone.
+    fn end_before(&self, start: usize, end: usize) -> usize {
+        let mut last = end - 1;
+
+        while last > start && self.token_at(last).typ == TokenType::Dedent {
+            last -= 1;
+        }
+
+        self.token_at(last).span.end
+    }
+
+    // Condition and action tokens include the `;` or `>`. This is the span of
+    // just the name.
+    fn name_span_at(&self, offset: usize) -> Span {
+        let token = self.token_at(offset);
+        let name_len = match token.typ {
+            TokenType::Condition(text) | TokenType::Action(text) => text.len(),
+            // the first token of a guard can be one of these too
+            TokenType::Not => "not".len(),
+            TokenType::OpenParen => "(".len(),
+            _ => token.span.end - token.span.start,
+        };
+
+        Span {
+            start: token.span.end - name_len,
+            end: token.span.end,
+        }
+    }
+
+    // Tokens are cheap to clone. They only borrow their text from the input.
+    // Handing out a reference would keep self.tokens borrowed, and then the
+    // lexer couldn't add to it.
+    fn get_token_at(&self, offset: usize) -> Option<Token<'a>> {
+        let mut tokens = self.tokens.borrow_mut();
+        let index = offset - self.forgotten.get();
+
+        while tokens.len() <= index {
+            // comments are only for people reading the chart.
+            // rust tip: If you want to match partially on a enum with a value
+            // In this case i didn't care about what's inside Comment enum
+            // variant
+            match self.lexer.borrow_mut().find(|t| !matches!(t.typ, TokenType::Comment(_))) {
+                Some(token) => tokens.push_back(token),
+                None => return None,
+            }
+        }
+
+        Some(tokens[index].clone())
+    }
+
+    // For tokens the parser has already looked at, so we know they are there.
+    fn token_at(&self, offset: usize) -> Token<'a> {
+        self.tokens.borrow()[offset - self.forgotten.get()].clone()
+    }
+
+    // Drops the tokens before offset, once nothing is going to backtrack to
+    // them. Two kinds of tokens before offset are still needed:
+    // - the furthest failure, which is where errors are reported
+    // - the last token which isn't a dedent, so end_before can find where
+    //   the state the tokens are in ends
+    fn forget_before(&self, offset: usize) {
+        let mut offset = offset.min(self.furthest_failure.borrow().offset);
+        while offset > self.forgotten.get() && self.token_at(offset - 1).typ == TokenType::Dedent {
+            offset -= 1;
+        }
+        let offset = offset.saturating_sub(1);
+
+        let forgotten = self.forgotten.get();
+        if offset > forgotten {
+            self.tokens.borrow_mut().drain(..offset - forgotten);
+            self.forgotten.set(offset);
+        }
+    }
+
+    fn has_token_at(&self, offset: usize) -> bool {
+        self.get_token_at(offset).is_some()
+    }
+
+    // This parser works for all parsers which want to compare token kind to 
     // something and return T if the comparison is successful
     // It helps take care of some repeating things
     // 1. use get_token_at and pull value out of the Option returned by that method
     // 2. Wrap the values in Option enum
-    fn match_parser<T, F, F2>(&self, offset: usize, pred: F, get_val: F2) -> Option<(usize, T)> 
+    // 3. Remember what was expected if the comparison fails
+    fn match_parser<T, F>(&self, offset: usize, kind: TokenKind, get_val: F) -> Option<(usize, T)>
         where
-            F: Fn(&Token<'a>) -> bool,
-            F2: Fn(&Token<'a>) -> T
+            F: Fn(&Token<'a>) -> T
     {
         if let Some(token) = self.get_token_at(offset) {
             // TODO: If i can change below if to `if let`, then i can pass 
@@ -237,11 +795,12 @@ impl<'a> Parser<'a> {
             // token.typ to enum and send back and Option
             // Maybe we can have get_val return an Option and always do
             // get_val(token).unwrap()
-            if pred(token) {
-                return Some((offset + 1, get_val(token)));
+            if token.typ.kind() == kind {
+                return Some((offset + 1, get_val(&token)));
             }
         }
 
+        self.expected(offset, kind);
         None
     }
 
@@ -252,83 +811,371 @@ impl<'a> Parser<'a> {
             }
         }
 
+        self.expected(offset, TokenKind::Identifier);
         None
     }
 
+    // `->` or `->>`. The bool is whether it's the internal one.
     fn transition_arrow(&self, offset: usize) -> Option<(usize, bool)> {
         if let Some(token) = self.get_token_at(offset) {
-            if let TokenType::TransitionArrow = token.typ {
-                return Some((offset + 1, true))
+            match token.typ {
+                TokenType::TransitionArrow => return Some((offset + 1, false)),
+                TokenType::InternalTransitionArrow => return Some((offset + 1, true)),
+                _ => {}
             }
         }
 
+        self.expected(offset, TokenKind::TransitionArrow);
+        self.expected(offset, TokenKind::InternalTransitionArrow);
         None
     }
     
-    fn condition(&self, offset: usize) -> Option<(usize, &'a str)> {
-        if let Some(token) = self.get_token_at(offset) {
-            if let TokenType::Condition(cond_str) = token.typ  {
-                return Some((offset + 1, cond_str));
+    // `or` binds looser than `and`, so a guard is a list of `and`s with `or`
+    // in between
+    fn guard(&self, offset: usize) -> Option<(usize, Guard<'a>)> {
+        self.guard_list(offset, TokenKind::Or, Guard::Or, |o| self.and_guard(o))
+    }
+
+    fn and_guard(&self, offset: usize) -> Option<(usize, Guard<'a>)> {
+        self.guard_list(offset, TokenKind::And, Guard::And, |o| self.guard_operand(o))
+    }
+
+    // One or more operands with operator in between. A single operand is
+    // returned as it is, so `a` is Named("a") and not And([Named("a")]).
+    fn guard_list<F>(
+        &self,
+        offset: usize,
+        operator: TokenKind,
+        combine: fn(Vec<Guard<'a>>) -> Guard<'a>,
+        operand: F,
+    ) -> Option<(usize, Guard<'a>)>
+        where
+            F: Fn(usize) -> Option<(usize, Guard<'a>)>
+    {
+        let (offset, first) = operand(offset)?;
+        let (offset, rest) = zero_or_more(offset, |o| {
+            let (o, _) = self.match_parser(o, operator, |_| true)?;
+            operand(o)
+        });
+
+        match rest {
+            Some(rest) => {
+                let mut guards = vec![first];
+                guards.extend(rest);
+                Some((offset, combine(guards)))
             }
+            None => Some((offset, first)),
         }
+    }
 
-        None
+    // `isValid`, `not isValid` or `(isValid or isAdmin)`
+    fn guard_operand(&self, offset: usize) -> Option<(usize, Guard<'a>)> {
+        match self.get_token_at(offset).map(|t| t.typ) {
+            Some(TokenType::Condition(name)) => {
+                let (offset, args) = self.call_arguments(offset + 1)?;
+                Some((offset, Guard::Named(Call { name, args })))
+            }
+            Some(TokenType::Not | TokenType::OpenParen) if self.guard_depth.get() == MAX_DEPTH => {
+                self.failed_with(offset, format!("guards can't be nested more than {} levels deep", MAX_DEPTH));
+                None
+            }
+            Some(TokenType::Not) => {
+                self.guard_depth.set(self.guard_depth.get() + 1);
+                let result = self.guard_operand(offset + 1);
+                self.guard_depth.set(self.guard_depth.get() - 1);
+
+                let (offset, guard) = result?;
+                Some((offset, Guard::Not(Box::new(guard))))
+            }
+            Some(TokenType::OpenParen) => {
+                self.guard_depth.set(self.guard_depth.get() + 1);
+                let result = self.guard(offset + 1);
+                self.guard_depth.set(self.guard_depth.get() - 1);
+
+                let (offset, guard) = result?;
+                let (offset, _) = self.match_parser(offset, TokenKind::CloseParen, |_| true)?;
+                Some((offset, guard))
+            }
+            _ => {
+                self.expected(offset, TokenKind::Condition);
+
+                // `not` and `(` are only `not` and `(` once there's a `;`
+                // before them. Don't suggest them at the start of a guard.
+                let in_guard = offset > 0 && matches!(
+                    self.token_at(offset - 1).typ,
+                    TokenType::And | TokenType::Or | TokenType::Not | TokenType::OpenParen
+                );
+                if in_guard {
+                    self.expected(offset, TokenKind::Not);
+                    self.expected(offset, TokenKind::OpenParen);
+                }
+
+                None
+            }
+        }
     }
 
-    fn action(&self, offset: usize) -> Option<(usize, &'a str)> {
+    fn action(&self, offset: usize) -> Option<(usize, Call<'a>)> {
         if let Some(token) = self.get_token_at(offset) {
-            if let TokenType::Action(cond_str) = token.typ  {
-                return Some((offset + 1, cond_str));
+            if let TokenType::Action(name) = token.typ  {
+                let (offset, args) = self.call_arguments(offset + 1)?;
+                return Some((offset, Call { name, args }));
             }
         }
 
+        self.expected(offset, TokenKind::Action);
         None
     }
 
+    // The arguments of the action or guard right before offset. Only a `(`
+    // with no space before it starts the arguments, so without one there
+    // are no arguments.
+    fn call_arguments(&self, offset: usize) -> Option<(usize, Vec<Argument<'a>>)> {
+        let is_call = self.get_token_at(offset).is_some_and(|token| {
+            token.typ == TokenType::OpenParen && token.span.start == self.token_at(offset - 1).span.end
+        });
+
+        if !is_call {
+            return Some((offset, vec![]));
+        }
+
+        let (offset, _) = self.match_parser(offset, TokenKind::OpenParen, |_| true)?;
+        let (mut offset, first) = zero_or_one(offset, |o| self.argument(o));
+        let mut args = vec![];
+
+        if let Some(first) = first {
+            let (new_offset, rest) = zero_or_more(offset, |o| {
+                let (o, _) = self.match_parser(o, TokenKind::Comma, |_| true)?;
+                self.argument(o)
+            });
+            offset = new_offset;
+            args.push(first);
+            args.extend(rest.unwrap_or_default());
+        }
+
+        let (offset, _) = self.match_parser(offset, TokenKind::CloseParen, |_| true)?;
+        Some((offset, args))
+    }
+
+    // `"entered"`, `level=2` or `admin`
+    fn argument(&self, offset: usize) -> Option<(usize, Argument<'a>)> {
+        let (offset, name) = zero_or_one(offset, |o| {
+            let (o, name) = self.identifier(o)?;
+            let (o, _) = self.match_parser(o, TokenKind::Equals, |_| true)?;
+            Some((o, name))
+        });
+
+        let value = match self.get_token_at(offset).map(|t| t.typ) {
+            Some(TokenType::String(text)) => Some(Literal::String(text)),
+            // The lexer lets through things like `1.2.3`. It's a number gone
+            // wrong, so `expected number, found number` won't help anyone.
+            Some(TokenType::Number(text)) => match text.parse() {
+                Ok(number) => Some(Literal::Number(number)),
+                Err(_) => {
+                    self.failed_with(offset, format!("`{}` is not a valid number", text));
+                    return None;
+                }
+            },
+            Some(TokenType::Identifier("true")) => Some(Literal::Bool(true)),
+            Some(TokenType::Identifier("false")) => Some(Literal::Bool(false)),
+            Some(TokenType::Identifier(text)) => Some(Literal::String(text)),
+            _ => None,
+        };
+
+        match value {
+            Some(value) => Some((offset + 1, Argument { name, value })),
+            None => {
+                self.expected(offset, TokenKind::String);
+                self.expected(offset, TokenKind::Number);
+                self.expected(offset, TokenKind::Identifier);
+                None
+            }
+        }
+    }
+
+    // the spans of the names of the actions between start and end, without
+    // their arguments
+    fn action_spans(&self, start: usize, end: usize) -> Vec<Span> {
+        (start..end)
+            .filter(|&o| self.token_at(o).typ.kind() == TokenKind::Action)
+            .map(|o| self.name_span_at(o))
+            .collect()
+    }
+
     fn parallel_state(&self, offset: usize) -> Option<(usize, bool)> {
-        self.match_parser(offset, |token| token.typ == TokenType::ParallelState, |_| true)
+        self.match_parser(offset, TokenKind::ParallelState, |_| true)
     }
 
     fn final_state(&self, offset: usize) -> Option<(usize, bool)> {
-        self.match_parser(offset, |token| token.typ == TokenType::FinalState, |_| true)
+        self.match_parser(offset, TokenKind::FinalState, |_| true)
     }
 
     fn initial_state(&self, offset: usize) -> Option<(usize, bool)> {
-        self.match_parser(offset, |token| token.typ == TokenType::InitialState, |_| true)
+        self.match_parser(offset, TokenKind::InitialState, |_| true)
+    }
+
+    fn history(&self, offset: usize) -> Option<(usize, HistoryMode)> {
+        if let Some(token) = self.get_token_at(offset) {
+            if let TokenType::History(mode) = token.typ {
+                return Some((offset + 1, mode));
+            }
+        }
+
+        self.expected(offset, TokenKind::History);
+        None
+    }
+
+    // the `-> step1` after a history marker
+    fn history_target(&self, offset: usize) -> Option<(usize, &'a str)> {
+        let (offset, _) = self.match_parser(offset, TokenKind::TransitionArrow, |_| true)?;
+        self.identifier(offset)
     }
 
     fn indent(&self, offset: usize) -> Option<(usize, bool)> {
-        self.match_parser(offset, |token| token.typ == TokenType::Indent, |_| true)
+        self.match_parser(offset, TokenKind::Indent, |_| true)
     }
 
 
     fn dedent(&self, offset: usize) -> Option<(usize, bool)> {
-        self.match_parser(offset, |token: &Token<'a>| token.typ == TokenType::Dedent, |_| true)
+        self.match_parser(offset, TokenKind::Dedent, |_| true)
+    }
+
+    // `entry > startTimer > log`, `exit > stopTimer` or `activity > beep`
+    fn action_list(&self, offset: usize) -> Option<(usize, BlockItem<'a>)> {
+        // rust tip: tuple variants of an enum are functions too
+        let item: fn(Vec<Call<'a>>, Vec<Span>) -> BlockItem<'a> =
+            match self.get_token_at(offset).map(|t| t.typ.kind()) {
+                Some(TokenKind::Entry) => BlockItem::Entry,
+                Some(TokenKind::Exit) => BlockItem::Exit,
+                Some(TokenKind::Activity) => BlockItem::Activity,
+                _ => {
+                    self.expected(offset, TokenKind::Entry);
+                    self.expected(offset, TokenKind::Exit);
+                    self.expected(offset, TokenKind::Activity);
+                    return None;
+                }
+            };
+        let actions_offset = offset + 1;
+        // at least one action, or what's the point
+        let (offset, actions) = zero_or_more(actions_offset, |o| self.action(o));
+        let actions = actions?;
+        let spans = self.action_spans(actions_offset, offset);
+
+        Some((offset, item(actions, spans)))
+    }
+
+    // `invoke src`, with the transitions for when the service is done or
+    // fails in an indented block under it
+    fn invoke(&mut self, offset: usize) -> Option<(usize, InvokeNode<'a>)> {
+        let start = offset;
+        let (offset, _) = self.match_parser(offset, TokenKind::Invoke, |_| true)?;
+        let src_offset = offset;
+        let (offset, src) = self.identifier(offset)?;
+        let (mut offset, is_indent_there) = zero_or_one(offset, |o| self.indent(o));
+        let mut transitions = vec![];
+
+        if is_indent_there.is_some() {
+            let (new_offset, transitions_option) = zero_or_more(offset, |o| self.transition(o));
+            let (new_offset, _) = self.dedent(new_offset)?;
+            offset = new_offset;
+            transitions = transitions_option.unwrap_or_default();
+        }
+
+        let mut on_done = vec![];
+        let mut on_error = vec![];
+
+        for transition in transitions {
+            match transition.event {
+                "done" => on_done.push(transition),
+                "error" => on_error.push(transition),
+                event => {
+                    let found = if event.is_empty() {
+                        "a transition without an event".to_string()
+                    } else {
+                        format!("`{}`", event)
+                    };
+                    self.push_error(
+                        transition.spans.transition,
+                        None,
+                        format!("invoke only has `done` and `error` transitions, found {}", found),
+                    );
+                }
+            }
+        }
+
+        Some((offset, InvokeNode {
+            src,
+            on_done,
+            on_error,
+            spans: InvokeSpans {
+                invoke: self.span_between(start, offset),
+                src: self.span_at(src_offset),
+            },
+        }))
+    }
+
+    // `after 3000ms`
+    fn after(&self, offset: usize) -> Option<(usize, u64)> {
+        let (offset, _) = self.match_parser(offset, TokenKind::After, |_| true)?;
+
+        if let Some(TokenType::Delay(text)) = self.get_token_at(offset).map(|t| t.typ) {
+            if let Some(delay) = parse_delay(text) {
+                return Some((offset + 1, delay));
+            }
+        }
+
+        self.expected(offset, TokenKind::Delay);
+        None
     }
 
     fn transition(&self, offset: usize) -> Option<(usize, TransitionNode<'a>)> {
+        let start = offset;
         let new_offset;
-        let (offset, event_option) = zero_or_one(offset, |offset| self.identifier(offset));
+        let (offset, delay_option) = zero_or_one(offset, |offset| self.after(offset));
+        let (offset, event_option) = match delay_option {
+            Some(_) => (offset, None),
+            None => zero_or_one(offset, |offset| self.identifier(offset)),
+        };
         let mut event = "";
-        let (offset, _) = self.transition_arrow(offset)?;
-        let (offset, target) = self.identifier(offset)?;
+        let target_offset = offset + 1;
+        // `click > increment` handles the event without going anywhere. Only
+        // transitions with an event can leave out the target, and then they
+        // need actions, or they would do nothing at all.
+        let (offset, target_option, internal) = match self.transition_arrow(offset) {
+            Some((offset, internal)) => {
+                let (offset, target) = self.identifier(offset)?;
+                (offset, Some(target), internal)
+            }
+            // without a target there's nowhere to go, so they are internal
+            None if event_option.is_some() || delay_option.is_some() => (offset, None, true),
+            None => return None,
+        };
+        let condition_offset = offset;
 
-        let condition_name;
+        let guard;
         let mut action_names = None;
+        let actions_offset;
 
-        if let Some(en) = event_option {
-            event = en;
-            let (offset, cn) = zero_or_one(offset, |offset| self.condition(offset));
-            condition_name = cn;
+        if event_option.is_some() || delay_option.is_some() {
+            event = event_option.unwrap_or("");
+            let (offset, g) = zero_or_one(offset, |offset| self.guard(offset));
+            guard = g;
+            actions_offset = offset;
             let (offset, ans) = zero_or_more(offset, |offset| self.action(offset));
+
+            if target_option.is_none() && ans.is_none() {
+                return None;
+            }
+
             action_names = ans;
             new_offset = offset;
         } else {
             // if the event name is not given, we definitely want the condition
             // It means it's a transient event and needs to be accompanied by
             // a condition
-            let (offset, condition_name_str) = self.condition(offset)?;
-            condition_name = Some(condition_name_str);
+            let (offset, g) = self.guard(offset)?;
+            guard = Some(g);
+            actions_offset = offset;
             let (offset, action_name_option) = zero_or_more(offset, |offset| self.action(offset));
 
             if let Some(action_name_strings) = action_name_option {
@@ -338,11 +1185,29 @@ impl<'a> Parser<'a> {
             new_offset = offset;
         }
 
+        // the guard goes from the first name, `not` or `(` after the `;` to
+        // right before the actions
+        let cond_span = guard.as_ref().map(|_| Span {
+            start: self.name_span_at(condition_offset).start,
+            end: self.token_at(actions_offset - 1).span.end,
+        });
+
         let transition_node = TransitionNode {
             event,
-            target,
-            cond: condition_name,
+            target: target_option.unwrap_or(""),
+            cond: cond_span.map(|span| &self.input_str[span.start..span.end]),
+            guard,
             actions: action_names,
+            delay: delay_option,
+            internal,
+            spans: TransitionSpans {
+                transition: self.span_between(start, new_offset),
+                event: event_option.map(|_| self.span_at(start)),
+                delay: delay_option.map(|_| self.span_at(start + 1)),
+                target: target_option.map(|_| self.span_at(target_offset)),
+                cond: cond_span,
+                actions: self.action_spans(actions_offset, new_offset),
+            },
         };
 
         Some((new_offset, transition_node))
@@ -356,74 +1221,162 @@ impl<'a> Parser<'a> {
     // We can use the question mark (?) operator
     // self.identifier()?;
     fn state_parser(&mut self, offset: usize) -> Option<(usize, StateNode<'a>)> {
+        let start = offset;
         let (offset, id) = self.identifier(offset)?;
+        let parallel_offset = offset;
         let (offset, is_parallel_state_option) =
             zero_or_one(offset, |offset| self.parallel_state(offset));
         // rust tip: Super way to get a value out of an option if we don't care 
         // about the absent value and have a default value as replacement.
         let is_parallel_state = is_parallel_state_option.unwrap_or(false);
 
+        let final_offset = offset;
         let (offset, is_final_state_option) =
             zero_or_one(offset, |o| self.final_state(o));
         let is_final_state = is_final_state_option.unwrap_or(false);
 
+        let initial_offset = offset;
         let (offset, is_initial_state_option) =
             zero_or_one(offset, |o| self.initial_state(o));
-        let is_initial_state = is_initial_state_option.unwrap_or(false);
+        let mut is_initial_state = is_initial_state_option.unwrap_or(false);
+
+        let history_offset = offset;
+        let (offset, history_option) = zero_or_one(offset, |o| self.history(o));
+        let history_target_offset = offset + 1;
+        let (offset, history_target) = match history_option {
+            Some(_) => zero_or_one(offset, |o| self.history_target(o)),
+            None => (offset, None),
+        };
+
+        // the rest are filled in once the block is parsed
+        let spans = StateSpans {
+            id: self.span_at(start),
+            parallel_marker: is_parallel_state_option.map(|_| self.span_at(parallel_offset)),
+            final_marker: is_final_state_option.map(|_| self.span_at(final_offset)),
+            initial_marker: is_initial_state_option.map(|_| self.span_at(initial_offset)),
+            history_marker: history_option.map(|_| self.span_at(history_offset)),
+            history_target: history_target.map(|_| self.span_at(history_target_offset)),
+            ..Default::default()
+        };
+
+        // same reason get_initial_state skips history states
+        if is_initial_state && history_option.is_some() {
+            let initial = self.token_at(initial_offset);
+            let mut error = ParseError::unexpected(initial.pos, Some(initial.typ), vec![]);
+            error.message = format!("history state `{}` can't be the initial state", id);
+            self.errors.push(error);
+            is_initial_state = false;
+        }
 
         let (mut offset, is_indent_there_option) = zero_or_one(offset, |o| self.indent(o));
         let is_indent_there = is_indent_there_option.unwrap_or(false);
         let mut transitions: Vec<TransitionNode<'a>>  = vec![];
         let mut sub_states: Vec<(&'a str, StateNode<'a>)> = vec![];
+        let (mut entry, mut entry_spans) = (vec![], vec![]);
+        let (mut exit, mut exit_spans) = (vec![], vec![]);
+        let (mut activities, mut activity_spans) = (vec![], vec![]);
+        let mut invoke = vec![];
+
+        if is_indent_there && self.depth == MAX_DEPTH {
+            let indent = self.token_at(offset - 1);
+            let mut error = ParseError::unexpected(indent.pos, Some(TokenType::Indent), vec![]);
+            error.message = format!("states can't be nested more than {} levels deep", MAX_DEPTH);
+            self.errors.push(error);
+            // skip the whole block, along with the dedent closing it
+            offset = self.skip_line(offset - 1);
+        } else if is_indent_there && history_option.is_some() {
+            // xstate doesn't do anything with what's in a history state, so
+            // it's a mistake whatever it is
+            let indent = self.token_at(offset - 1);
+            let mut error = ParseError::unexpected(indent.pos, Some(TokenType::Indent), vec![]);
+            error.message = format!("history state `{}` can't have anything indented under it", id);
+            self.errors.push(error);
+            offset = self.skip_line(offset - 1);
+        } else if is_indent_there {
+            self.depth += 1;
+            let mut block_items = vec![];
+            let mut new_offset = offset;
+
+            loop {
+                // Had to create a separate enum to hold either TransitionNode or
+                // StateNode or the rest
+                let (o, block_items_option) = zero_or_more(new_offset, |o| -> Option<(usize, BlockItem)> {
+                    // the items before this one are done with
+                    self.forget_before(o);
+
+                    if let Some(item) = self.action_list(o) {
+                        return Some(item);
+                    }
 
-        if is_indent_there {
-            // Had to create a separate enum to hold either TransitionNode or 
-            // StateNode. And then it became super painful to take them apart.
-            let (new_offset, transitions_and_states_option) = zero_or_more(offset, |o| -> Option<(usize, TransitionOrState)> {
-                if let Some((no, x)) = self.transition(o) {
-                    return Some((no, TransitionOrState::Transition(x)));
-                }
+                    if let Some((no, x)) = self.invoke(o) {
+                        return Some((no, BlockItem::Invoke(x)));
+                    }
+
+                    if let Some((no, x)) = self.transition(o) {
+                        return Some((no, BlockItem::Transition(x)));
+                    }
 
-                if let Some((no, x)) = self.state_parser(o) {
-                    return Some((no, TransitionOrState::State(x)));
+                    if let Some((no, x)) = self.state_parser(o) {
+                        return Some((no, BlockItem::State(Box::new(x))));
+                    }
+
+                    None
+                });
+                new_offset = o;
+                block_items.extend(block_items_option.unwrap_or_default());
+
+                // Not recovering means whatever stopped us is for the parent
+                // state to deal with. Otherwise skip the line we are stuck on
+                // and carry on, unless we are stuck because the block is over.
+                let is_block_over = match self.get_token_at(new_offset) {
+                    None => true,
+                    Some(token) => token.typ == TokenType::Dedent,
+                };
+                if !self.recover || is_block_over {
+                    break;
                 }
 
-                return None;
-            });
+                new_offset = self.recover_from(new_offset);
+            }
 
-            if let Some(transitions_and_states) = transitions_and_states_option {
-                // Had to clone the list because otherwise rust complains that
-                // the values are already moved when i try to get the states
-                // in the second filter pass
-                let transitions_and_states_clone = transitions_and_states.clone();
-                transitions = transitions_and_states
-                    .into_iter()
-                    .filter_map(|ts| match ts {
-                        // we can convert a vector to hashmap by having the vector as a
-                        // vector of tuples of (key, val)
-                        TransitionOrState::Transition(t) => Some(t),
-                        _ => None,
-                    })
-                    .collect();
-                sub_states = transitions_and_states_clone
-                    .into_iter()
-                    .filter_map(|ts| match ts {
-                        TransitionOrState::State(t) => Some((t.id, t)),
-                        _ => None,
-                    })
-                    .collect();
+            for item in block_items {
+                match item {
+                    BlockItem::Transition(t) => transitions.push(t),
+                    // we can convert a vector to hashmap by having the vector as a
+                    // vector of tuples of (key, val)
+                    BlockItem::State(s) => sub_states.push((s.id, *s)),
+                    BlockItem::Entry(actions, spans) => {
+                        entry.extend(actions);
+                        entry_spans.extend(spans);
+                    }
+                    BlockItem::Exit(actions, spans) => {
+                        exit.extend(actions);
+                        exit_spans.extend(spans);
+                    }
+                    BlockItem::Activity(names, spans) => {
+                        activities.extend(names);
+                        activity_spans.extend(spans);
+                    }
+                    BlockItem::Invoke(i) => invoke.push(i),
+                }
             }
 
-            zero_or_more(new_offset, |o| self.dedent(o));
+            self.remove_duplicate_siblings(&mut sub_states);
             offset = new_offset;
 
             let (new_offset, _) = zero_or_one(offset, |o| self.dedent(o));
             offset = new_offset;
+            self.depth -= 1;
         }
 
         Some((offset, StateNode {
             id,
-            typ: get_state_type(is_parallel_state, is_final_state, sub_states.len()),
+            typ: get_state_type(
+                is_parallel_state,
+                is_final_state,
+                history_option.map(|mode| (mode, history_target)),
+                sub_states.len(),
+            ),
             initial: get_initial_state(&sub_states),
             is_initial: is_initial_state,
             // we can convert a vector to hashmap by having the vector as a
@@ -433,34 +1386,208 @@ impl<'a> Parser<'a> {
             // Because all the transient transitions have the same empty string
             // key
             on: transitions,
+            entry,
+            exit,
+            activities,
+            invoke,
             states: sub_states.into_iter().collect(),
+            spans: StateSpans {
+                // the tokens of the block may be gone by now, along with the
+                // one at start
+                state: Span {
+                    start: spans.id.start,
+                    end: self.end_before(start, offset),
+                },
+                entry: entry_spans,
+                exit: exit_spans,
+                activities: activity_spans,
+                ..spans
+            },
         }))
     }
 
+    fn start(&mut self, input_str: &'a str) {
+        self.lexer = RefCell::new(Lexer::with_options(input_str, self.options));
+        self.tokens = RefCell::new(VecDeque::new());
+        self.forgotten = Cell::new(0);
+        self.furthest_failure = RefCell::new(Failure::default());
+        self.input_str = input_str;
+        self.errors = vec![];
+        self.line_starts = RefCell::new(vec![]);
+        self.depth = 0;
+        self.guard_depth = Cell::new(0);
+    }
+
+    // For errors about things which parsed fine, but aren't right
+    fn push_error(&mut self, span: Span, found: Option<TokenType<'a>>, message: String) {
+        let mut error = ParseError::unexpected(self.position_at(span.start), found, vec![]);
+        error.message = message;
+        self.errors.push(error);
+    }
+
+    // Reports `what` named `name` at `span` as a duplicate of the one at
+    // `first`.
+    fn duplicate_error(&mut self, what: &str, name: &'a str, span: Span, first: Span) {
+        let first = self.position_at(first.start);
+        let message = format!(
+            "duplicate {} `{}`, first declared at line {}, col {}",
+            what,
+            name,
+            first.line_number + 1,
+            first.col + 1
+        );
+        self.push_error(span, Some(TokenType::Identifier(name)), message);
+
+        if let Some(error) = self.errors.last_mut() {
+            error.related = Some(first);
+        }
+    }
+
+    // Sub states are looked up by name, so a second one with the same name
+    // would quietly take the place of the first one. Keep the first one and
+    // report the rest.
+    fn remove_duplicate_siblings(&mut self, sub_states: &mut Vec<(&'a str, StateNode<'a>)>) {
+        // where the ones we kept are, by name
+        let mut seen: HashMap<&'a str, Span> = HashMap::new();
+        let mut kept = Vec::with_capacity(sub_states.len());
+
+        for (name, state) in std::mem::take(sub_states) {
+            match seen.get(name) {
+                Some(first) => self.duplicate_error("state", name, state.spans.id, *first),
+                None => {
+                    seen.insert(name, state.spans.id);
+                    kept.push((name, state));
+                }
+            }
+        }
+
+        *sub_states = kept;
+    }
+
+    // A state written as `#name` has an explicit id, which targets like
+    // `#name.child` use to find it from anywhere in the chart. The root state's
+    // name is the id of the whole machine. All of them have to be unique.
+    fn check_ids(&mut self, root: &StateNode<'a>) {
+        let mut ids: Vec<(&'a str, Span)> = vec![(root.key(), root.spans.id)];
+        let mut to_visit: Vec<&StateNode<'a>> = root.states.values().collect();
+
+        while let Some(state) = to_visit.pop() {
+            if let Some(id) = state.explicit_id() {
+                ids.push((id, state.spans.id));
+            }
+            to_visit.extend(state.states.values());
+        }
+
+        // report the later ones, whatever order we found them in
+        ids.sort_by_key(|(_, span)| span.start);
+
+        let mut seen: HashMap<&'a str, Span> = HashMap::new();
+
+        for (id, span) in ids {
+            match seen.get(id) {
+                Some(first) => self.duplicate_error("id", id, span, *first),
+                None => {
+                    seen.insert(id, span);
+                }
+            }
+        }
+    }
+
     // Our parser returns a Result type. Which means it returns an error if the
     // parsing fails.
-    // TODO: Define a custom error struct
-    pub fn parse(&mut self, input_str: &'a str) -> Result<StateNode<'a>, &'a str> {
-        self.tokens = tokenize(input_str)
-            .into_iter()
-            // rust tip: If you want to match partially on a enum with a value
-            // In this case i didn't care about what's inside Comment enum 
-            // variant
-            .filter(|t| !matches!(t.typ, TokenType::Comment(_)))
-            .collect();
+    // ParseError is on the big side for an Err, but parse is called once per
+    // chart, not in a loop, so there's nothing to gain from boxing it.
+    #[allow(clippy::result_large_err)]
+    pub fn parse(&mut self, input_str: &'a str) -> Result<StateNode<'a>, ParseError<'a>> {
+        self.start(input_str);
+        self.recover = false;
+
+        let result = self.state_parser(0);
+
+        match &result {
+            // The whole chart is one root state. Anything left after it is
+            // either a second root state or something the state parser
+            // choked on and gave up. Either way it's an error, otherwise
+            // that part of the chart silently goes missing.
+            Some((offset, _)) if self.has_token_at(*offset) => {
+                let error = self.trailing_tokens_error(*offset);
+                self.errors.push(error);
+            }
+            Some((_, ast)) => self.check_ids(ast),
+            None => {
+                let error = self.error_at_furthest_failure();
+                self.errors.push(error);
+            }
+        }
 
-        if let Some((_, ast)) = self.state_parser(0) {
-            // println!("ast {:#?}", ast);
-            return Ok(ast);
+        // Along with the failure, there may be errors state_parser ran into
+        // and could not turn into a failure, and problems with the
+        // indentation. A badly indented line usually makes the parser fail
+        // somewhere further down, so the first error in the input is the
+        // one to report.
+        match (self.take_errors().into_iter().next(), result) {
+            (Some(error), _) => Err(error),
+            (None, Some((_, ast))) => Ok(ast),
+            // state_parser failing always leaves an error behind
+            (None, None) => Err(self.error_at_furthest_failure()),
+        }
+    }
+
+    // All the errors so far, including the ones from the lexer, in the order
+    // they are in the input. sort_by_key keeps the order of errors at the
+    // same offset.
+    fn take_errors(&mut self) -> Vec<ParseError<'a>> {
+        let mut errors = std::mem::take(&mut self.errors);
+        errors.extend(self.lexer.borrow().errors().iter().cloned());
+        errors.sort_by_key(|error| error.pos.offset);
+
+        errors
+    }
+
+    // Like parse, but doesn't stop at the first error. Lines which don't parse
+    // are skipped and reported, and we get back whatever could be parsed
+    // around them. Editors want this so they can show all the broken lines
+    // at once.
+    pub fn parse_with_errors(&mut self, input_str: &'a str) -> (Option<StateNode<'a>>, Vec<ParseError<'a>>) {
+        self.start(input_str);
+        self.recover = true;
+
+        let mut root = None;
+        let mut offset = 0;
+
+        while self.has_token_at(offset) {
+            // Anything after the root state is an error, same as in parse.
+            // It has to be worked out before parsing what's there, which
+            // moves the furthest failure past it.
+            let extra_root_error = root.as_ref().map(|_| self.trailing_tokens_error(offset));
+
+            match (self.state_parser(offset), extra_root_error) {
+                (Some((new_offset, ast)), None) => {
+                    root = Some(ast);
+                    offset = new_offset;
+                }
+                // a second root state
+                (Some((new_offset, _)), Some(error)) => {
+                    self.errors.push(error);
+                    offset = new_offset;
+                }
+                (None, _) => offset = self.recover_from(offset),
+            }
+        }
+
+        if let Some(root) = &root {
+            self.check_ids(root);
         }
 
-        Err("MyParser: Error parsing string")
+        self.recover = false;
+        (root, self.take_errors())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     static INPUT: &str = "abc
 % some comment
@@ -477,6 +1604,637 @@ mod tests {
     -> ast; ifyes
     -> lastState; ifno";
 
+    // Most tests don't care about where things are in the input
+    fn without_spans(mut state: StateNode) -> StateNode {
+        state.spans = StateSpans::default();
+
+        for transition in state.on.iter_mut() {
+            transition.spans = TransitionSpans::default();
+        }
+
+        state.states = state
+            .states
+            .into_iter()
+            .map(|(id, sub_state)| (id, without_spans(sub_state)))
+            .collect();
+
+        state
+    }
+
+    #[test]
+    fn test_spans() {
+        let input = "abc
+  ast&*
+    opq -> rst; ifyes > doThis > doThat
+    -> lastState; ifno
+  lastState$";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let text = |span: Span| &input[span.start..span.end];
+
+        assert_eq!(input, text(ast.spans.state));
+        assert_eq!("abc", text(ast.spans.id));
+
+        let ast_state = &ast.states["ast"];
+        assert_eq!(
+            "ast&*\n    opq -> rst; ifyes > doThis > doThat\n    -> lastState; ifno",
+            text(ast_state.spans.state)
+        );
+        assert_eq!("&", text(ast_state.spans.parallel_marker.unwrap()));
+        assert_eq!(None, ast_state.spans.final_marker);
+        assert_eq!("*", text(ast_state.spans.initial_marker.unwrap()));
+
+        let transition = &ast_state.on[0].spans;
+        assert_eq!("opq -> rst; ifyes > doThis > doThat", text(transition.transition));
+        assert_eq!("opq", text(transition.event.unwrap()));
+        assert_eq!("rst", text(transition.target.unwrap()));
+        assert_eq!("ifyes", text(transition.cond.unwrap()));
+        let actions: Vec<&str> = transition.actions.iter().map(|span| text(*span)).collect();
+        assert_eq!(vec!["doThis", "doThat"], actions);
+
+        let transient = &ast_state.on[1].spans;
+        assert_eq!("-> lastState; ifno", text(transient.transition));
+        assert_eq!(None, transient.event);
+
+        let last_state = &ast.states["lastState"];
+        assert_eq!("lastState$", text(last_state.spans.state));
+        assert_eq!("$", text(last_state.spans.final_marker.unwrap()));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let mut parser = Parser::new();
+
+        let error = parser.parse("-> abc").unwrap_err();
+        assert_eq!(Position { line_number: 0, col: 0, offset: 0 }, error.pos);
+        assert_eq!(Some(TokenType::TransitionArrow), error.found);
+        assert_eq!(vec![TokenKind::Identifier], error.expected);
+        assert_eq!("line 1, col 1: expected identifier, found `->`", error.to_string());
+
+        let error = parser.parse("% only a comment").unwrap_err();
+        assert_eq!(Position { line_number: 0, col: 16, offset: 16 }, error.pos);
+        assert_eq!(None, error.found);
+        assert_eq!("expected identifier, found end of input", error.message);
+    }
+
+    #[test]
+    fn test_trailing_tokens() {
+        let mut parser = Parser::new();
+
+        // a second root state
+        let error = parser.parse("abc\n  def -> lmn\nghi").unwrap_err();
+        assert_eq!(Position { line_number: 2, col: 0, offset: 17 }, error.pos);
+        assert_eq!(Some(TokenType::Identifier("ghi")), error.found);
+        assert_eq!("unexpected identifier `ghi` after the end of the root state", error.message);
+
+        // a transition without a target
+        let error = parser.parse("abc\n  def ->\n  lmn ->").unwrap_err();
+        assert_eq!(Position { line_number: 2, col: 8, offset: 21 }, error.pos);
+        assert_eq!(Some(TokenType::Dedent), error.found);
+        assert_eq!(vec![TokenKind::Identifier], error.expected);
+
+        // a stray character
+        let error = parser.parse("abc\n  def -> lmn ^").unwrap_err();
+        assert_eq!(Position { line_number: 1, col: 13, offset: 17 }, error.pos);
+        assert_eq!(Some(TokenType::Unknown("unknown")), error.found);
+
+        // parse_with_errors reports a second root state the same way, even
+        // when the state itself has a broken line in it
+        for input in ["abc\n  x\nghi\n  y\n  z", "abc\n  def\nxyz -> q"] {
+            let error = parser.parse(input).unwrap_err();
+            let (_, errors) = parser.parse_with_errors(input);
+            assert_eq!(Position { line_number: 2, col: 0, offset: error.pos.offset }, error.pos);
+            assert_eq!(error, errors[0]);
+        }
+    }
+
+    #[test]
+    fn test_parse_with_errors() {
+        let input = "abc
+  def -> lmn ^
+  ok -> fine
+  ghi -> @
+  mno
+    -> pqr
+  last";
+        let mut parser = Parser::new();
+        let (ast, errors) = parser.parse_with_errors(input);
+        let positions: Vec<Position> = errors.iter().map(|e| e.pos.clone()).collect();
+
+        assert_eq!(
+            vec![
+                Position { line_number: 1, col: 13, offset: 17 },
+                Position { line_number: 3, col: 9, offset: 41 },
+                Position { line_number: 6, col: 2, offset: 62 },
+            ],
+            positions
+        );
+        assert_eq!(Some(TokenType::Unknown("unknown")), errors[0].found);
+        assert_eq!(vec![TokenKind::Identifier], errors[1].expected);
+        assert_eq!(vec![TokenKind::Condition], errors[2].expected);
+
+        let ast = ast.unwrap();
+        let events: Vec<&str> = ast.on.iter().map(|t| t.event).collect();
+        assert_eq!(vec!["def", "ok"], events);
+        assert_eq!(3, ast.states.len());
+        assert!(ast.states.contains_key("last"));
+
+        // a clean chart parses the same either way
+        let (ast, errors) = parser.parse_with_errors("abc\n  def -> lmn");
+        assert_eq!(parser.parse("abc\n  def -> lmn").ok(), ast);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_states() {
+        let input = "abc
+  def
+    inner
+  ghi
+  def
+  #pasta
+    #abc";
+        let mut parser = Parser::new();
+
+        let error = parser.parse(input).unwrap_err();
+        assert_eq!(Position { line_number: 4, col: 2, offset: 28 }, error.pos);
+        assert_eq!(Some(Position { line_number: 1, col: 2, offset: 6 }), error.related);
+        assert_eq!("duplicate state `def`, first declared at line 2, col 3", error.message);
+
+        let (ast, errors) = parser.parse_with_errors(input);
+        let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            vec![
+                "duplicate state `def`, first declared at line 2, col 3",
+                "duplicate id `abc`, first declared at line 1, col 1",
+            ],
+            messages
+        );
+
+        // the first one is kept, along with its sub states
+        let ast = ast.unwrap();
+        assert_eq!(vec!["def", "ghi", "#pasta"], ast.states.keys().copied().collect::<Vec<&str>>());
+        assert_eq!(1, ast.states["def"].states.len());
+
+        // the same name in different parents is fine
+        assert!(parser.parse("abc\n  def\n    x\n  ghi\n    x").is_ok());
+    }
+
+    #[test]
+    fn test_indentation_errors() {
+        let input = "abc
+  def
+    ghi
+   jkl
+  mno
+        pqr";
+        let mut parser = Parser::new();
+
+        // jkl lines up with nothing above it
+        let error = parser.parse(input).unwrap_err();
+        assert_eq!(Position { line_number: 3, col: 3, offset: 21 }, error.pos);
+        assert_eq!("unindent does not match any outer indentation level", error.message);
+
+        let (ast, errors) = parser.parse_with_errors(input);
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "line 4, col 4: unindent does not match any outer indentation level",
+                "line 6, col 9: indented by 6 columns, but the lines above are indented by 2",
+            ],
+            messages
+        );
+        // it goes with the state it's indented past
+        assert_eq!(vec!["def", "jkl", "mno"], ast.unwrap().states.keys().copied().collect::<Vec<&str>>());
+
+        // comments can be indented any which way
+        let ast = parser.parse("abc\n  def\n      % too far\n  ghi").unwrap();
+        assert_eq!(vec!["def", "ghi"], ast.states.keys().copied().collect::<Vec<&str>>());
+        let ast = parser.parse("abc\n  def\n    x\n % not far enough\n    y").unwrap();
+        assert_eq!(vec!["x", "y"], ast.states["def"].states.keys().copied().collect::<Vec<&str>>());
+    }
+
+    #[test]
+    fn test_tab_indentation() {
+        let mut parser = Parser::new();
+        let with_spaces = parser.parse("abc\n  def -> ghi\n  ghi\n    jkl").unwrap();
+        let with_tabs = parser.parse("abc\n\tdef -> ghi\n\tghi\n\t\tjkl").unwrap();
+        assert_eq!(without_spans(with_spaces), without_spans(with_tabs));
+
+        let mut parser = Parser::with_options(ParserOptions {
+            indentation: Indentation::SpacesOrTabs,
+            tab_width: 2,
+            strict: true,
+        });
+        let error = parser.parse("abc\n\tdef -> ghi\n  ghi").unwrap_err();
+        assert_eq!("line 3, col 1: indentation mixes tabs and spaces", error.to_string());
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let mut parser = Parser::new();
+        let with_lf = parser.parse("abc\n  def -> ghi\n  ghi").unwrap();
+        let with_crlf = parser.parse("\u{FEFF}abc\r\n  def -> ghi\r\n  ghi\r\n").unwrap();
+        assert_eq!(without_spans(with_lf), without_spans(with_crlf));
+
+        let error = parser.parse("\u{FEFF}abc\r\n  def ->\r\n").unwrap_err();
+        assert_eq!(Position { line_number: 2, col: 0, offset: 18 }, error.pos);
+        let error = parser.parse("\u{FEFF}abc\r  def\r  def").unwrap_err();
+        assert_eq!(Position { line_number: 2, col: 2, offset: 15 }, error.pos);
+        assert_eq!(Some(Position { line_number: 1, col: 2, offset: 9 }), error.related);
+    }
+
+    #[test]
+    fn test_entry_exit() {
+        let input = "abc
+  entry > connect
+  loading
+    entry > startTimer > showSpinner
+    exit > stopTimer
+    entry > log
+    done -> exit
+  exit$";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let text = |span: Span| &input[span.start..span.end];
+
+        assert_eq!(vec![Call::from("connect")], ast.entry);
+        let loading = &ast.states["loading"];
+        assert_eq!(
+            vec![Call::from("startTimer"), Call::from("showSpinner"), Call::from("log")],
+            loading.entry
+        );
+        assert_eq!(vec![Call::from("stopTimer")], loading.exit);
+        assert_eq!("showSpinner", text(loading.spans.entry[1]));
+        assert_eq!("stopTimer", text(loading.spans.exit[0]));
+
+        // entry and exit are still fine as names
+        assert_eq!("exit", loading.on[0].target);
+        assert!(ast.states.contains_key("exit"));
+
+        let error = parser.parse("abc\n  entry >").unwrap_err();
+        assert_eq!("line 2, col 9: expected action (`>`), found unknown character", error.to_string());
+    }
+
+    #[test]
+    fn test_invoke_and_activities() {
+        let input = "abc
+  loading
+    activity > beep > blink
+    invoke fetchUser
+      done -> success > saveUser
+      error -> failure
+      error -> failure; isOffline
+    invoke logVisit
+    cancel -> idle
+  invoke";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let text = |span: Span| &input[span.start..span.end];
+
+        let loading = &ast.states["loading"];
+        assert_eq!(vec![Call::from("beep"), Call::from("blink")], loading.activities);
+        assert_eq!("blink", text(loading.spans.activities[1]));
+        assert_eq!(2, loading.invoke.len());
+
+        let fetch = &loading.invoke[0];
+        assert_eq!("fetchUser", fetch.src);
+        assert_eq!("fetchUser", text(fetch.spans.src));
+        assert_eq!(vec!["success"], fetch.on_done.iter().map(|t| t.target).collect::<Vec<_>>());
+        assert_eq!(Some(vec![Call::from("saveUser")]), fetch.on_done[0].actions);
+        assert_eq!(2, fetch.on_error.len());
+        assert_eq!(Some("isOffline"), fetch.on_error[1].cond);
+
+        let log = &loading.invoke[1];
+        assert_eq!("logVisit", log.src);
+        assert!(log.on_done.is_empty() && log.on_error.is_empty());
+        assert_eq!("invoke logVisit", text(log.spans.invoke).trim_end());
+
+        // the block under invoke doesn't take the state's transitions
+        assert_eq!("idle", loading.on[0].target);
+        // a lone `invoke` is still a state
+        assert!(ast.states.contains_key("invoke"));
+
+        let (_, errors) = parser.parse_with_errors("abc\n  invoke fetchUser\n    done -> a\n    click -> b\n  a\n  b");
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec!["line 4, col 5: invoke only has `done` and `error` transitions, found `click`"],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_guards() {
+        let input = "form
+  submit -> done; isValid and not isLocked or isAdmin > save
+  reset -> form; not (isDirty or isSaving)
+  -> done;(isValid)
+  done";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let named = |name| Box::new(Guard::Named(Call::from(name)));
+
+        let submit = &ast.on[0];
+        assert_eq!(
+            Some(Guard::Or(vec![
+                Guard::And(vec![Guard::Named(Call::from("isValid")), Guard::Not(named("isLocked"))]),
+                Guard::Named(Call::from("isAdmin")),
+            ])),
+            submit.guard
+        );
+        assert_eq!(Some("isValid and not isLocked or isAdmin"), submit.cond);
+        assert_eq!(submit.cond, submit.spans.cond.map(|span| &input[span.start..span.end]));
+        assert_eq!(Some(vec![Call::from("save")]), submit.actions);
+
+        let reset = &ast.on[1];
+        assert_eq!("not (isDirty or isSaving)", reset.guard.as_ref().unwrap().to_string());
+        assert_eq!(Some("not (isDirty or isSaving)"), reset.cond);
+
+        assert_eq!(Some(Guard::Named(Call::from("isValid"))), ast.on[2].guard);
+        assert_eq!(Some("(isValid)"), ast.on[2].cond);
+
+        let error = parser.parse("form\n  submit -> done; isValid and > save\n  done").unwrap_err();
+        assert_eq!(
+            "line 2, col 31: expected condition (`;`), `not` or `(`, found action `save`",
+            error.to_string()
+        );
+        let error = parser.parse("form\n  submit -> done; (isValid\n  done").unwrap_err();
+        assert_eq!("line 3, col 3: expected `and`, `or` or `)`, found identifier `done`", error.to_string());
+    }
+
+    #[test]
+    fn test_call_arguments() {
+        let input = "abc
+  entry > log(\"entered\", level=2, loud=true) > track()
+  submit -> abc; hasRole(admin) and not over(-1.5) > save";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+
+        assert_eq!(
+            Call {
+                name: "log",
+                args: vec![
+                    Argument { name: None, value: Literal::String("entered") },
+                    Argument { name: Some("level"), value: Literal::Number(2.0) },
+                    Argument { name: Some("loud"), value: Literal::Bool(true) },
+                ],
+            },
+            ast.entry[0]
+        );
+        assert_eq!(Call::from("track"), ast.entry[1]);
+        // the spans are just the names
+        let text = |span: Span| &input[span.start..span.end];
+        assert_eq!(vec!["log", "track"], ast.spans.entry.iter().map(|s| text(*s)).collect::<Vec<_>>());
+
+        let submit = &ast.on[0];
+        assert_eq!("hasRole(\"admin\") and not over(-1.5)", submit.guard.as_ref().unwrap().to_string());
+        assert_eq!(Some("hasRole(admin) and not over(-1.5)"), submit.cond);
+        assert_eq!(Some(vec![Call::from("save")]), submit.actions);
+
+        let error = parser.parse("abc\n  go -> abc > log(level=)").unwrap_err();
+        assert_eq!(
+            "line 2, col 25: expected string, number or identifier, found `)`",
+            error.to_string()
+        );
+        let error = parser.parse("abc\n  go -> abc > log(a b)").unwrap_err();
+        assert_eq!("line 2, col 21: expected `=`, `,` or `)`, found identifier `b`", error.to_string());
+
+        let error = parser.parse("abc\n  go -> abc > f(1.2.3)").unwrap_err();
+        assert_eq!("line 2, col 17: `1.2.3` is not a valid number", error.to_string());
+    }
+
+    #[test]
+    fn test_targetless_transitions() {
+        let input = "counter
+  entry > reset, log
+  increment > add(1), log
+  change; isValid > store
+  after 1s > tick
+  exit > save
+  done -> counter > save, close
+  increment";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let names = |calls: &Option<Vec<Call>>| -> Vec<String> {
+            calls.iter().flatten().map(|call| call.to_string()).collect()
+        };
+
+        assert_eq!(vec![Call::from("reset"), Call::from("log")], ast.entry);
+        assert_eq!(vec![Call::from("save")], ast.exit);
+
+        let increment = &ast.on[0];
+        assert_eq!(("increment", ""), (increment.event, increment.target));
+        assert_eq!(None, increment.spans.target);
+        assert_eq!(vec!["add(1)", "log"], names(&increment.actions));
+        let text = |span: Span| &input[span.start..span.end];
+        assert_eq!(vec!["add", "log"], increment.spans.actions.iter().map(|s| text(*s)).collect::<Vec<_>>());
+
+        assert_eq!(Some("isValid"), ast.on[1].cond);
+        assert_eq!("", ast.on[1].target);
+        assert_eq!((Some(1000), ""), (ast.on[2].delay, ast.on[2].target));
+        assert_eq!(vec!["save", "close"], names(&ast.on[3].actions));
+        assert_eq!(4, ast.on.len());
+
+        // without actions it's still a state
+        assert!(ast.states.contains_key("increment"));
+
+        // an arrow still needs its target
+        let error = parser.parse("counter\n  increment -> > add").unwrap_err();
+        assert_eq!("line 2, col 16: expected identifier, found action `add`", error.to_string());
+    }
+
+    #[test]
+    fn test_internal_transitions() {
+        let input = "form
+  edit ->> form > clearErrors
+  reset -> form
+  save ->> .saving
+  bump > count
+  saving";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let internal: Vec<(&str, bool)> = ast.on.iter().map(|t| (t.event, t.internal)).collect();
+
+        assert_eq!(
+            vec![("edit", true), ("reset", false), ("save", true), ("bump", true)],
+            internal
+        );
+        assert_eq!("form", ast.on[0].target);
+        assert_eq!(Some("form"), ast.on[0].spans.target.map(|span| &input[span.start..span.end]));
+    }
+
+    #[test]
+    fn test_delayed_transitions() {
+        let input = "abc
+  idle
+    after 3000ms -> def
+    after 1.5s -> def; isBored > yawn
+  def";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let on = &ast.states["idle"].on;
+
+        assert_eq!(Some(3000), on[0].delay);
+        assert_eq!("", on[0].event);
+        assert_eq!(None, on[0].spans.event);
+        assert_eq!(Some("3000ms"), on[0].spans.delay.map(|span| &input[span.start..span.end]));
+        assert_eq!(Some(1500), on[1].delay);
+        assert_eq!(Some("isBored"), on[1].cond);
+        assert_eq!(Some(vec![Call::from("yawn")]), on[1].actions);
+
+        assert_eq!(Some(5), parse_delay("5ms"));
+        assert_eq!(None, parse_delay("5"));
+        assert_eq!(None, parse_delay("1e3ms"));
+        assert_eq!(None, parse_delay("99999999999999999999999s"));
+        assert_eq!(None, parse_delay("18446744073709551616ms"));
+
+        let error = parser.parse("abc\n  after 5min -> abc").unwrap_err();
+        assert_eq!(
+            "line 2, col 9: expected delay in `ms` or `s`, found delay `5min`",
+            error.to_string()
+        );
+
+        // not a state called `after`
+        let error = parser.parse("abc\n  after .s -> abc").unwrap_err();
+        assert_eq!("line 2, col 9: expected delay in `ms` or `s`, found delay `.s`", error.to_string());
+
+        // too long to be a number of milliseconds we can hold
+        let error = parser.parse("abc\n  after 99999999999999999999999s -> abc").unwrap_err();
+        assert_eq!(
+            "line 2, col 9: expected delay in `ms` or `s`, found delay `99999999999999999999999s`",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_history_states() {
+        let input = "wizard
+  step1*
+    next -> step2
+  step2
+  resume H -> step2
+  resumeAll H*";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let text = |span: Option<Span>| span.map(|span| &input[span.start..span.end]);
+
+        let resume = &ast.states["resume"];
+        assert_eq!(StateType::History { mode: HistoryMode::Shallow, target: Some("step2") }, resume.typ);
+        assert_eq!(Some("H"), text(resume.spans.history_marker));
+        assert_eq!(Some("step2"), text(resume.spans.history_target));
+
+        let resume_all = &ast.states["resumeAll"];
+        assert_eq!(StateType::History { mode: HistoryMode::Deep, target: None }, resume_all.typ);
+        assert_eq!(Some("H*"), text(resume_all.spans.history_marker));
+        assert_eq!(Some("step1"), ast.initial);
+
+        let (ast, errors) = parser.parse_with_errors("wizard\n  resume H\n    step1\n  step2");
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec!["line 3, col 5: history state `resume` can't have anything indented under it"],
+            messages
+        );
+        let ast = ast.unwrap();
+        assert!(ast.states["resume"].states.is_empty());
+        assert!(ast.states.contains_key("step2"));
+
+        // history states are never where the parent starts
+        let ast = parser.parse("wizard\n  resume H\n  step1\n  step2").unwrap();
+        assert_eq!(Some("step1"), ast.initial);
+        let ast = parser.parse("wizard\n  resume H").unwrap();
+        assert_eq!(None, ast.initial);
+
+        let (ast, errors) = parser.parse_with_errors("wizard\n  resume* H\n  step1");
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec!["line 2, col 9: history state `resume` can't be the initial state"], messages);
+        assert_eq!(Some("step1"), ast.unwrap().initial);
+    }
+
+    #[test]
+    fn test_lazy_tokenizing() {
+        let mut input = String::from("abc\n  def -> ; oops\n");
+        for i in 0..1000 {
+            input.push_str(&format!("  state{} -> abc\n", i));
+        }
+
+        let mut parser = Parser::new();
+        assert!(parser.parse(&input).is_err());
+        // parsing stopped at line 2, so the lexer never got to the rest
+        assert!(parser.tokens.borrow().len() < 10);
+        // A chart which parses fine is tokenized all the way, but only the
+        // tokens since the last line are kept around
+        let mut input = String::from("abc\n");
+        for i in 0..1000 {
+            input.push_str(&format!("  state{}\n    go -> state{}; isReady > log\n", i, i));
+        }
+
+        let ast = parser.parse(&input).unwrap();
+        assert_eq!(1000, ast.states.len());
+        assert!(parser.tokens.borrow().len() < 10);
+        assert_eq!(
+            Span {
+                start: 0,
+                end: input.trim_end().len(),
+            },
+            ast.spans.state
+        );
+    }
+
+    #[test]
+    fn test_deeply_nested_states() {
+        let input: Vec<String> = (0..150).map(|i| format!("{}s{}", " ".repeat(i), i)).collect();
+        let input = input.join("\n");
+        let mut parser = Parser::new();
+
+        let error = parser.parse(&input).unwrap_err();
+        let offset = input.lines().take(MAX_DEPTH + 1).map(|line| line.len() + 1).sum::<usize>() + MAX_DEPTH + 1;
+        assert_eq!(Position { line_number: MAX_DEPTH + 1, col: MAX_DEPTH + 1, offset }, error.pos);
+        assert_eq!("states can't be nested more than 100 levels deep", error.message);
+
+        let (ast, errors) = parser.parse_with_errors(&input);
+        assert!(ast.is_some());
+        assert_eq!(1, errors.len());
+    }
+
+    #[test]
+    fn test_deeply_nested_guards() {
+        for nesting in ["(", "not "] {
+            let input = format!("abc\n  go -> abc; {}x", nesting.repeat(10_000));
+            let mut parser = Parser::new();
+
+            let error = parser.parse(&input).unwrap_err();
+            // the guard starts at col 13, and the error is at the first
+            // `not` or `(` past the limit
+            let col = 13 + nesting.len() * MAX_DEPTH;
+            assert_eq!(Position { line_number: 1, col, offset: col + 4 }, error.pos);
+            assert_eq!("guards can't be nested more than 100 levels deep", error.message);
+
+            let (_, errors) = parser.parse_with_errors(&input);
+            assert_eq!(1, errors.len());
+            assert_eq!("guards can't be nested more than 100 levels deep", errors[0].message);
+        }
+
+        // right at the limit is fine
+        let input = format!("abc\n  go -> abc; {}x{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        assert!(Parser::new().parse(&input).is_ok());
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(input in "\\PC*") {
+            let mut parser = Parser::new();
+            let _ = parser.parse(&input);
+            let _ = parser.parse_with_errors(&input);
+        }
+
+        #[test]
+        fn parse_never_panics_on_statecharts(input in "([a-z#._ ]{0,8}[-;>&$*%()]{0,2}[ \t\r\n]{0,3}){0,30}") {
+            let mut parser = Parser::new();
+            let _ = parser.parse(&input);
+            let _ = parser.parse_with_errors(&input);
+        }
+    }
+
     #[test]
     fn test_parser() {
         let mut parser = Parser::new();
@@ -492,46 +2250,38 @@ mod tests {
                     event: "def",
                     target: "lmn",
                     cond: None,
-                    actions: None
+                    guard: None,
+                    actions: None,
+                    delay: None,
+                    internal: false,
+                    spans: Default::default(),
                 },
                 TransitionNode {
                     event: "pasta",
                     target: "noodles",
                     cond: None,
-                    actions: None
+                    guard: None,
+                    actions: None,
+                    delay: None,
+                    internal: false,
+                    spans: Default::default(),
                 },
                 TransitionNode {
                     event: "tried",
                     target: "that",
                     cond: None,
-                    actions: Some(vec!["andDoThis"])
+                    guard: None,
+                    actions: Some(vec![Call::from("andDoThis")]),
+                    delay: None,
+                    internal: false,
+                    spans: Default::default(),
                 }
             ],
+            entry: vec![],
+            exit: vec![],
+            activities: vec![],
+            invoke: vec![],
             states: vec![
-                (
-                    "lastState", 
-                    StateNode {
-                        id: "lastState",
-                        typ: StateType::AtomicState,
-                        initial: None,
-                        is_initial: false,
-                        on: vec![
-                            TransitionNode {
-                                event: "",
-                                target: "ast",
-                                cond: Some("ifyes"),
-                                actions: None
-                            },
-                            TransitionNode {
-                                event: "",
-                                target: "lastState",
-                                cond: Some("ifno"),
-                                actions: None
-                            }
-                        ],
-                        states: HashMap::new()
-                    }
-                ),
                 (
                     "ast",
                     StateNode {
@@ -544,39 +2294,62 @@ mod tests {
                                 event: "opq",
                                 target: "rst",
                                 cond: Some("ifyes"),
+                                guard: Some(Guard::Named(Call::from("ifyes"))),
                                 actions: None,
+                                delay: None,
+                                internal: false,
+                                spans: Default::default(),
                             },
                             TransitionNode {
                                 event: "uvw",
                                 target: "#abc.lastState",
                                 cond: None,
+                                guard: None,
                                 actions: None,
+                                delay: None,
+                                internal: false,
+                                spans: Default::default(),
                             },
                         ],
+                        entry: vec![],
+                        exit: vec![],
+                        activities: vec![],
+                        invoke: vec![],
                         states: vec![
                             (
-                                "nestedstate2",
+                                "nestedstate1",
                                 StateNode {
-                                    id: "nestedstate2",
+                                    id: "nestedstate1",
                                     typ: StateType::AtomicState,
                                     initial: None,
-                                    is_initial: true,
+                                    is_initial: false,
                                     on: vec![],
-                                    states: HashMap::new()
-                                },
+                                    entry: vec![],
+                                    exit: vec![],
+                                    activities: vec![],
+                                    invoke: vec![],
+                                    states: IndexMap::new(),
+                                    spans: Default::default(),
+                                }
                             ),
                             (
-                                "nestedstate1",
+                                "nestedstate2",
                                 StateNode {
-                                    id: "nestedstate1",
+                                    id: "nestedstate2",
                                     typ: StateType::AtomicState,
                                     initial: None,
-                                    is_initial: false,
+                                    is_initial: true,
                                     on: vec![],
-                                    states: HashMap::new()
-                                }
+                                    entry: vec![],
+                                    exit: vec![],
+                                    activities: vec![],
+                                    invoke: vec![],
+                                    states: IndexMap::new(),
+                                    spans: Default::default(),
+                                },
                             )
-                        ].into_iter().collect()
+                        ].into_iter().collect(),
+                        spans: Default::default(),
                     }
                 ),
                 (
@@ -591,21 +2364,40 @@ mod tests {
                                 event: "",
                                 target: "ast",
                                 cond: Some("ifyes"),
+                                guard: Some(Guard::Named(Call::from("ifyes"))),
                                 actions: None,
+                                delay: None,
+                                internal: false,
+                                spans: Default::default(),
                             },
                             TransitionNode {
                                 event: "",
                                 target: "lastState",
                                 cond: Some("ifno"),
+                                guard: Some(Guard::Named(Call::from("ifno"))),
                                 actions: None,
+                                delay: None,
+                                internal: false,
+                                spans: Default::default(),
                             }
                         ],
-                        states: HashMap::new()
+                        entry: vec![],
+                        exit: vec![],
+                        activities: vec![],
+                        invoke: vec![],
+                        states: IndexMap::new(),
+                        spans: Default::default(),
                     }
                 )
             ].into_iter().collect(),
+            spans: Default::default(),
         };
 
-        assert_eq!(expected_ast, ast);
+        // IndexMaps compare equal whatever order their keys are in
+        let names = |state: &StateNode<'static>| -> Vec<&'static str> { state.states.keys().copied().collect() };
+        assert_eq!(vec!["ast", "lastState"], names(&ast));
+        assert_eq!(vec!["nestedstate1", "nestedstate2"], names(&ast.states["ast"]));
+
+        assert_eq!(expected_ast, without_spans(ast));
     }
 }
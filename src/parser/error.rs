@@ -0,0 +1,67 @@
+use std::error::Error;
+use std::fmt;
+
+use super::tokenizer::{Position, TokenKind, TokenType};
+
+// rubber-duck item 10. Instead of a plain string we tell the user where the
+// parser got stuck, what it found there and what it would have accepted.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseError<'a> {
+    pub pos: Position,
+    // None when the parser ran out of tokens
+    pub found: Option<TokenType<'a>>,
+    pub expected: Vec<TokenKind>,
+    pub message: String,
+    // Some errors are about two places in the input. E.g. for a duplicate
+    // state this is where the first one is.
+    pub related: Option<Position>,
+}
+
+impl<'a> ParseError<'a> {
+    pub fn unexpected(pos: Position, found: Option<TokenType<'a>>, expected: Vec<TokenKind>) -> Self {
+        let found_text = match &found {
+            Some(typ) => typ.to_string(),
+            None => "end of input".to_string(),
+        };
+
+        let message = if expected.is_empty() {
+            format!("unexpected {}", found_text)
+        } else {
+            format!("expected {}, found {}", expected_text(&expected), found_text)
+        };
+
+        ParseError {
+            pos,
+            found,
+            expected,
+            message,
+            related: None,
+        }
+    }
+}
+
+// [a] -> "a", [a, b] -> "a or b", [a, b, c] -> "a, b or c"
+fn expected_text(expected: &[TokenKind]) -> String {
+    let kinds: Vec<String> = expected.iter().map(|kind| kind.to_string()).collect();
+
+    match kinds.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
+
+impl<'a> fmt::Display for ParseError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // positions start at 0, but editors show lines and columns from 1
+        write!(
+            f,
+            "line {}, col {}: {}",
+            self.pos.line_number + 1,
+            self.pos.col + 1,
+            self.message
+        )
+    }
+}
+
+impl<'a> Error for ParseError<'a> {}
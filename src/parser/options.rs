@@ -0,0 +1,29 @@
+// Which characters lines can be indented with
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Indentation {
+    // A tab goes to the next multiple of tab_width, like it does in an editor
+    SpacesOrTabs,
+    SpacesOnly,
+    TabsOnly,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ParserOptions {
+    pub indentation: Indentation,
+    // how many columns a tab is worth
+    pub tab_width: usize,
+    // Also report things which parse fine, but often aren't what was meant.
+    // E.g. a chart indented with tabs in some places and spaces in others
+    // only nests the way it looks with the right tab width.
+    pub strict: bool,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions {
+            indentation: Indentation::SpacesOrTabs,
+            tab_width: 4,
+            strict: false,
+        }
+    }
+}
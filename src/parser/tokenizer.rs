@@ -1,4 +1,10 @@
-use regex::Regex;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::error::ParseError;
+use super::options::{Indentation, ParserOptions};
+use super::HistoryMode;
 
 // How do i print my structs and enums?
 // There are 2 ways
@@ -20,104 +26,468 @@ pub enum TokenType<'a> {
     FinalState,
     InitialState,
     TransitionArrow,
+    // `->>`, for transitions which don't leave the state they start in
+    InternalTransitionArrow,
+    Entry,
+    Exit,
+    Activity,
+    Invoke,
+    History(HistoryMode),
+    After,
+    // the `3000ms` or `5s` after `after`
+    Delay(&'a str),
+    // the operators and parentheses of guards
+    And,
+    Or,
+    Not,
+    OpenParen,
+    CloseParen,
+    // the arguments of actions and guards, e.g. `log("entered", level=2)`
+    String(&'a str),
+    Number(&'a str),
+    Comma,
+    Equals,
 }
 
+// TokenType without the text. The parser uses it to tell what it was
+// expecting when it fails to parse something.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TokenKind {
+    Identifier,
+    Condition,
+    Indent,
+    Dedent,
+    Unknown,
+    Comment,
+    Action,
+    ParallelState,
+    FinalState,
+    InitialState,
+    TransitionArrow,
+    InternalTransitionArrow,
+    Entry,
+    Exit,
+    Activity,
+    Invoke,
+    History,
+    After,
+    Delay,
+    And,
+    Or,
+    Not,
+    OpenParen,
+    CloseParen,
+    String,
+    Number,
+    Comma,
+    Equals,
+}
+
+impl<'a> TokenType<'a> {
+    pub fn kind(&self) -> TokenKind {
+        match self {
+            TokenType::Identifier(_) => TokenKind::Identifier,
+            TokenType::Condition(_) => TokenKind::Condition,
+            TokenType::Indent => TokenKind::Indent,
+            TokenType::Dedent => TokenKind::Dedent,
+            TokenType::Unknown(_) => TokenKind::Unknown,
+            TokenType::Comment(_) => TokenKind::Comment,
+            TokenType::Action(_) => TokenKind::Action,
+            TokenType::ParallelState => TokenKind::ParallelState,
+            TokenType::FinalState => TokenKind::FinalState,
+            TokenType::InitialState => TokenKind::InitialState,
+            TokenType::TransitionArrow => TokenKind::TransitionArrow,
+            TokenType::InternalTransitionArrow => TokenKind::InternalTransitionArrow,
+            TokenType::Entry => TokenKind::Entry,
+            TokenType::Exit => TokenKind::Exit,
+            TokenType::Activity => TokenKind::Activity,
+            TokenType::Invoke => TokenKind::Invoke,
+            TokenType::History(_) => TokenKind::History,
+            TokenType::After => TokenKind::After,
+            TokenType::Delay(_) => TokenKind::Delay,
+            TokenType::And => TokenKind::And,
+            TokenType::Or => TokenKind::Or,
+            TokenType::Not => TokenKind::Not,
+            TokenType::OpenParen => TokenKind::OpenParen,
+            TokenType::CloseParen => TokenKind::CloseParen,
+            TokenType::String(_) => TokenKind::String,
+            TokenType::Number(_) => TokenKind::Number,
+            TokenType::Comma => TokenKind::Comma,
+            TokenType::Equals => TokenKind::Equals,
+        }
+    }
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            TokenKind::Identifier => "identifier",
+            TokenKind::Condition => "condition (`;`)",
+            TokenKind::Indent => "indent",
+            TokenKind::Dedent => "dedent",
+            TokenKind::Unknown => "unknown character",
+            TokenKind::Comment => "comment",
+            TokenKind::Action => "action (`>`)",
+            TokenKind::ParallelState => "`&`",
+            TokenKind::FinalState => "`$`",
+            TokenKind::InitialState => "`*`",
+            TokenKind::TransitionArrow => "`->`",
+            TokenKind::InternalTransitionArrow => "`->>`",
+            TokenKind::Entry => "`entry`",
+            TokenKind::Exit => "`exit`",
+            TokenKind::Activity => "`activity`",
+            TokenKind::Invoke => "`invoke`",
+            TokenKind::History => "`H` or `H*`",
+            TokenKind::After => "`after`",
+            TokenKind::Delay => "delay in `ms` or `s`",
+            TokenKind::And => "`and`",
+            TokenKind::Or => "`or`",
+            TokenKind::Not => "`not`",
+            TokenKind::OpenParen => "`(`",
+            TokenKind::CloseParen => "`)`",
+            TokenKind::String => "string",
+            TokenKind::Number => "number",
+            TokenKind::Comma => "`,`",
+            TokenKind::Equals => "`=`",
+        };
+
+        write!(f, "{}", text)
+    }
+}
+
+impl<'a> fmt::Display for TokenType<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenType::Identifier(text) => write!(f, "identifier `{}`", text),
+            TokenType::Condition(text) => write!(f, "condition `{}`", text),
+            TokenType::Action(text) => write!(f, "action `{}`", text),
+            TokenType::Delay(text) => write!(f, "delay `{}`", text),
+            TokenType::String(text) => write!(f, "string \"{}\"", text),
+            TokenType::Number(text) => write!(f, "number `{}`", text),
+            TokenType::History(HistoryMode::Shallow) => write!(f, "`H`"),
+            TokenType::History(HistoryMode::Deep) => write!(f, "`H*`"),
+            typ => write!(f, "{}", typ.kind()),
+        }
+    }
+}
+
+// line_number and col both start at 0. col counts characters, not bytes, so
+// it's what an editor shows for lines with non ascii text in them. offset is
+// the byte offset from the start of the input, for slicing the input.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Position {
-    line_number: usize,
-    col: usize,
+    pub line_number: usize,
+    pub col: usize,
+    pub offset: usize,
+}
+
+// Byte offsets of the start and end of something in the input. end is
+// exclusive, so &input[span.start..span.end] is the text it covers.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Token<'a> {
     pub typ: TokenType<'a>,
     pub pos: Position,
+    pub span: Span,
+}
+
+// A line of the input along with where it sits in the input. All the offsets
+// the tokenizer works with are byte offsets into line.text. Slicing a &str
+// with anything else panics as soon as there's a non ascii character around.
+struct Line<'a> {
+    text: &'a str,
+    number: usize,
+    // byte offset of the start of the line in the input
+    start: usize,
+    // The byte offset and column of the last position handed out. Tokens are
+    // made from left to right, so counting the characters from there instead
+    // of from the start of the line means the line is only counted once.
+    last_col: Cell<(usize, usize)>,
+}
+
+impl<'a> Line<'a> {
+    fn new(text: &'a str, number: usize, start: usize) -> Self {
+        Line {
+            text,
+            number,
+            start,
+            last_col: Cell::new((0, 0)),
+        }
+    }
+
+    fn char_at(&self, offset: usize) -> Option<char> {
+        self.text[offset..].chars().next()
+    }
+
+    fn position(&self, offset: usize) -> Position {
+        let (last_offset, last_col) = self.last_col.get();
+        let col = if offset >= last_offset {
+            last_col + self.text[last_offset..offset].chars().count()
+        } else {
+            self.text[..offset].chars().count()
+        };
+        self.last_col.set((offset, col));
+
+        Position {
+            line_number: self.number,
+            col,
+            offset: self.start + offset,
+        }
+    }
+
+    fn token(&self, start: usize, end: usize, typ: TokenType<'a>) -> Token<'a> {
+        Token {
+            pos: self.position(start),
+            span: Span {
+                start: self.start + start,
+                end: self.start + end,
+            },
+            typ,
+        }
+    }
 }
 
-// Instead of having a Token type with line and col, maybe it's better to rename
-// TokenType to Token, convert the lexer to an iterator where the parser keeps
-// asking for the next token. And when the parser needs it, most probably during
-// an error, the parser can ask the lexer for the current line and column
-// It will also make our lexer more performant because it will not go through
-// the whole text and get all tokens. It will do so lazily. In most cases when
-// there's an error in the initial parts of the string or in the middle, it
-// won't waste time parsing the rest of the string.
+fn comment_token<'a>(line: &Line<'a>, offset: usize) -> Token<'a> {
+    line.token(offset, line.text.len(), TokenType::Comment(&line.text[offset..]))
+}
 
-fn comment_token(line_number: usize, offset: usize, input: &str) -> Token {
-    let text = &input[offset..];
+// `>` and `,` are followed by the name of an action, maybe with some
+// whitespace in between. Returns the offset after the name and the name, or
+// None if there's no name after the marker.
+fn name_after_marker<'a>(line: &Line<'a>, offset: usize) -> Option<(usize, &'a str)> {
+    // the markers are ascii, so they are one byte long
+    let mut name_offset = offset + 1;
 
-    get_token(line_number, offset, TokenType::Comment(text))
+    while let Some(c) = line.char_at(name_offset) {
+        if !c.is_whitespace() {
+            break;
+        }
+        name_offset += c.len_utf8();
+    }
+
+    match line.char_at(name_offset) {
+        Some(c) if is_identifier_start(c) => {
+            let text = identifier_text(line, name_offset);
+            Some((name_offset + text.len(), text))
+        }
+        _ => None,
+    }
 }
 
-// TODO: move the code to get identifier text to another function
-fn condition_token(line_number: usize, mut offset: usize, input: &str) -> (usize, Token) {
-    let input_as_chars: Vec<char> = input.chars().collect();
+// `; isValid and not (isLocked or isBusy)`. The guard goes on until the
+// actions or the end of the line, and the names in it are conditions. The
+// first token takes the `;` along with it, the way a lone condition always
+// did. Returns the offset after the guard.
+fn guard_tokens<'a>(line: &Line<'a>, offset: usize, tokens: &mut VecDeque<Token<'a>>) -> usize {
+    let marker = offset;
+    // the markers are ascii, so they are one byte long
+    let mut offset = offset + 1;
+    let mut first = true;
 
-    let mut c = input_as_chars[offset];
+    loop {
+        while let Some(c) = line.char_at(offset) {
+            if !c.is_whitespace() {
+                break;
+            }
+            offset += c.len_utf8();
+        }
 
-    while !is_identifier_start(c) {
-        c = input_as_chars[offset];
-        offset += 1;
+        let (end, typ) = match line.char_at(offset) {
+            Some('(') => (offset + 1, TokenType::OpenParen),
+            Some(')') => (offset + 1, TokenType::CloseParen),
+            Some(c) if is_identifier_start(c) => {
+                let text = identifier_text(line, offset);
+                let typ = match text {
+                    "and" => TokenType::And,
+                    "or" => TokenType::Or,
+                    "not" => TokenType::Not,
+                    _ => TokenType::Condition(text),
+                };
+                (offset + text.len(), typ)
+            }
+            _ => break,
+        };
+
+        let is_call = matches!(typ, TokenType::Condition(_)) && line.char_at(end) == Some('(');
+        let start = if first { marker } else { offset };
+        tokens.push_back(line.token(start, end, typ));
+        offset = end;
+        first = false;
+
+        // `hasRole(admin)`. The `(` right after a name is for its arguments,
+        // anywhere else it groups guards.
+        if is_call {
+            offset = argument_tokens(line, offset, tokens);
+        }
     }
-    offset -= 1;
-    let identifier = identifier_token(line_number, offset, input);
 
-    let text = match identifier.typ {
-        TokenType::Identifier(t) => t,
-        _ => " ",
-    };
+    // a `;` without a guard is not something we understand
+    if first {
+        tokens.push_back(line.token(marker, marker + 1, TokenType::Unknown("unknown")));
+        return marker + 1;
+    }
 
-    // TODO: The offset sent to get_token is wrong here. We are mutating it.
-    (
-        offset + text.len(),
-        get_token(line_number, offset, TokenType::Condition(text)),
-    )
+    offset
 }
 
-fn action_token(line_number: usize, mut offset: usize, input: &str) -> (usize, Token) {
-    let input_as_chars: Vec<char> = input.chars().collect();
+// `> save`, `> log("entered", level=2)` or `> validate, save, notify`. Every
+// action is a token of its own, which takes the `>` or `,` in front of it
+// along with it. Returns the offset after the actions and their arguments.
+fn action_tokens<'a>(line: &Line<'a>, offset: usize, tokens: &mut VecDeque<Token<'a>>) -> usize {
+    let mut marker = offset;
 
-    let mut c = input_as_chars[offset];
+    while let Some((name_end, text)) = name_after_marker(line, marker) {
+        tokens.push_back(line.token(marker, name_end, TokenType::Action(text)));
 
-    while !is_identifier_start(c) {
-        c = input_as_chars[offset];
-        offset += 1;
+        let end = if line.char_at(name_end) == Some('(') {
+            argument_tokens(line, name_end, tokens)
+        } else {
+            name_end
+        };
+
+        // is there a `,` and another action after this one?
+        let rest = &line.text[end..];
+        let comma = end + (rest.len() - rest.trim_start().len());
+        if line.char_at(comma) != Some(',') {
+            return end;
+        }
+        marker = comma;
+    }
+
+    // a `>` or `,` without an action name is not something we understand
+    tokens.push_back(line.token(marker, marker + 1, TokenType::Unknown("unknown")));
+    marker + 1
+}
+
+// `("entered", level=2)`, starting at the `(`. Stops after the `)`, or at
+// the first thing which can't be in there. The parser complains about that.
+// Strings are everything between the quotes, there's no escaping in them.
+fn argument_tokens<'a>(line: &Line<'a>, offset: usize, tokens: &mut VecDeque<Token<'a>>) -> usize {
+    tokens.push_back(line.token(offset, offset + 1, TokenType::OpenParen));
+    let mut offset = offset + 1;
+
+    while let Some(c) = line.char_at(offset) {
+        let (end, typ) = match c {
+            c if c.is_whitespace() => {
+                offset += c.len_utf8();
+                continue;
+            }
+            ')' => {
+                tokens.push_back(line.token(offset, offset + 1, TokenType::CloseParen));
+                return offset + 1;
+            }
+            ',' => (offset + 1, TokenType::Comma),
+            '=' => (offset + 1, TokenType::Equals),
+            '"' => match line.text[offset + 1..].find('"') {
+                Some(len) => (offset + len + 2, TokenType::String(&line.text[offset + 1..offset + 1 + len])),
+                None => break,
+            },
+            '-' if line.char_at(offset + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                let end = offset + 1 + number_len(&line.text[offset + 1..]);
+                (end, TokenType::Number(&line.text[offset..end]))
+            }
+            c if c.is_ascii_digit() => {
+                let end = offset + number_len(&line.text[offset..]);
+                (end, TokenType::Number(&line.text[offset..end]))
+            }
+            c if is_identifier_start(c) => {
+                let text = identifier_text(line, offset);
+                (offset + text.len(), TokenType::Identifier(text))
+            }
+            _ => break,
+        };
+
+        tokens.push_back(line.token(offset, end, typ));
+        offset = end;
     }
-    offset -= 1;
-    let identifier = identifier_token(line_number, offset, input);
 
-    let text = match identifier.typ {
-        TokenType::Identifier(t) => t,
-        _ => " ",
-    };
+    offset
+}
+
+// The keywords are only keywords at the start of a line, in front of what
+// has to come after them. A `>` for `entry`, `exit` and `activity`, the
+// name of a service for `invoke` and a delay for `after`. Anywhere else
+// they are names like any other, so charts which already have states or
+// events called that keep working.
+fn line_keyword<'a>(line: &Line<'a>, line_start: usize, offset: usize, text: &str) -> Option<TokenType<'a>> {
+    if offset != line_start {
+        return None;
+    }
 
-    // TODO: The offset sent to get_token is wrong here. We are mutating it.
-    (
-        offset + text.len(),
-        get_token(line_number, offset, TokenType::Action(text)),
-    )
+    let rest = line.text[offset + text.len()..].trim_start();
+    let next = rest.chars().next();
+    let next_word = &rest[..rest.find(|c| !is_identifier_start(c)).unwrap_or(rest.len())];
+
+    match (text, next) {
+        ("entry", Some('>')) => Some(TokenType::Entry),
+        ("exit", Some('>')) => Some(TokenType::Exit),
+        ("activity", Some('>')) => Some(TokenType::Activity),
+        ("invoke", Some(c)) if is_identifier_start(c) => Some(TokenType::Invoke),
+        // Any word is taken as the delay, so that one the parser doesn't
+        // understand, e.g. `after .5s`, is an error instead of a state
+        // called `after`. Except for the `H` of a history state.
+        ("after", Some(c)) if is_identifier_start(c) && next_word != "H" => Some(TokenType::After),
+        _ => None,
+    }
 }
 
-fn identifier_token(line_number: usize, offset: usize, input: &str) -> Token {
-    let text = &input[offset..]
-        .split(|c| is_identifier_start(c) == false)
-        .collect::<Vec<&str>>()[0];
+// `H` or `H*` right after the name of the state which starts the line, e.g.
+// `lastStep H*`. Returns where the marker ends, along with the token.
+// Anywhere else `H` is a name, and `lastStepH` is one name anyway.
+// The `*` of an initial state can go in between too, so that the parser can
+// tell `lastStep* H` is a history state and not two states on one line.
+fn history_marker<'a>(line: &Line<'a>, line_start: usize, offset: usize, text: &str) -> Option<(usize, TokenType<'a>)> {
+    if text != "H" || offset == line_start {
+        return None;
+    }
 
-    get_token(line_number, offset, TokenType::Identifier(text))
+    let name = identifier_text(line, line_start);
+    let between = &line.text[line_start + name.len()..offset];
+    if !between.trim_matches(|c: char| c.is_whitespace() || c == '*').is_empty() {
+        return None;
+    }
+
+    match line.char_at(offset + 1) {
+        Some('*') => Some((offset + 2, TokenType::History(HistoryMode::Deep))),
+        _ => Some((offset + 1, TokenType::History(HistoryMode::Shallow))),
+    }
+}
+
+// digits, with maybe a `.` in there. `2abc` is the number `2` and then
+// something else.
+fn number_len(text: &str) -> usize {
+    text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len())
+}
+
+fn identifier_text<'a>(line: &Line<'a>, offset: usize) -> &'a str {
+    let rest = &line.text[offset..];
+    let end = rest.find(|c| !is_identifier_start(c)).unwrap_or(rest.len());
+
+    &rest[..end]
 }
 
 fn is_identifier_start(c: char) -> bool {
-    // How do i use regex in rust?
-    // rust does not support regular expressions (regex) out of the box
-    // We have to use an external library
-    let re = Regex::new(r"^[#a-zA-Z0-9_\.]").unwrap();
-
-    // How do i convert character to string?
-    // is_match method expects a string
-    // Ans -> use `to_string()`
-    // But to_string returns a String, but we want a &str
-    // How do i convert String to &str?
-    re.is_match(&c.to_string()[..])
+    // This used to be a regex. Matching a regex against every character of
+    // the input was most of the time spent tokenizing, and char already knows
+    // about letters and digits from any script.
+    // The combining marks some scripts need (e.g. an e followed by a
+    // combining accent) are mostly alphabetic too. The ones which aren't are
+    // the generic accents, which live in the ranges below.
+    c.is_alphanumeric()
+        || matches!(
+            c,
+            '#' | '_' | '.'
+                | '\u{0300}'..='\u{036F}'
+                | '\u{1AB0}'..='\u{1AFF}'
+                | '\u{1DC0}'..='\u{1DFF}'
+                | '\u{20D0}'..='\u{20FF}'
+                | '\u{FE20}'..='\u{FE2F}'
+        )
 }
 
 // this is the key function in the tokenizer
@@ -125,202 +495,333 @@ fn is_identifier_start(c: char) -> bool {
 // This is the whole reason i had to write a tokenizer in a recursive descent
 // parser.
 // This step in the tokenizer makes life much simpler for the parser.
+// Problems with the indentation are added to errors. The tokens are still
+// the best guess at what was meant, so the parser can carry on.
 fn indent_dedent_tokens<'a>(
-    line_number: usize,
-    indent_stack: &mut Vec<usize>,
-    line: &Vec<char>,
+    line: &Line<'a>,
+    cursor: &mut Cursor<'a>,
+    errors: &mut Vec<ParseError<'a>>,
+    options: &ParserOptions,
 ) -> (usize, Vec<Token<'a>>) {
     let mut offset = 0;
     let mut current_indent_level: usize = 0;
     let mut tokens: Vec<Token> = Vec::new();
+    // a tab width of 0 would make tabs not indent at all
+    let tab_width = options.tab_width.max(1);
 
-    while line[offset] == ' ' {
-        current_indent_level += 1;
+    while let Some(c) = line.char_at(offset) {
+        match c {
+            ' ' => current_indent_level += 1,
+            '\t' => current_indent_level += tab_width - current_indent_level % tab_width,
+            _ => break,
+        }
         offset += 1;
     }
 
-    if current_indent_level > 0 {
-        match indent_stack.last() {
-            None => {
-                // it's the first indent we have encountered
-                // or - all indents have been deindented
-                indent_stack.push(current_indent_level);
-                tokens.push(get_token(line_number, offset, TokenType::Indent));
-            }
-            Some(&prev_indent_level) => {
-                if prev_indent_level < current_indent_level {
-                    indent_stack.push(current_indent_level);
-                    tokens.push(get_token(line_number, offset, TokenType::Indent));
-                } else if prev_indent_level > current_indent_level {
-                    // TODO: we should implement some syntax error checking
-                    // in this part. E.g. previous indent level is 2 and the
-                    // current one is 6. It's too much.
-                    // Or the one below
-                    // const dedentLevelInStack = indentStack.find(
-                    // (n) => n === currentIndentLevel,
-                    // );
-
-                    // // any dedent/outdent must match some previous indentation level.
-                    // // otherwise it's a syntax error
-                    // if (dedentLevelInStack === undefined) {
-                    // throw new Error('Invalid indentation');
-                    // }
-
-                    while indent_stack.len() > 0 {
-                        let prev_indent = indent_stack.pop().unwrap();
-                        // keep popping indentation levels from indent dedentLevelInStack
-                        // until we reach the current indent level
-                        // push those many dedent tokens to tokenizer
-                        if prev_indent > current_indent_level {
-                            tokens.push(get_token(line_number, offset, TokenType::Dedent));
-                        } else {
-                            indent_stack.push(prev_indent);
-                            break;
-                        }
-                    }
-                }
+    // Blank lines don't open or close a state. Neither do lines with only a
+    // comment on them, however far they are indented.
+    match line.char_at(offset) {
+        None | Some('%') => return (offset, tokens),
+        _ => {}
+    }
+
+    // where the first character the options don't allow is
+    let bad_offset = line.text[..offset].char_indices().find_map(|(i, c)| {
+        let allowed = match options.indentation {
+            Indentation::SpacesOnly => c == ' ',
+            Indentation::TabsOnly => c == '\t',
+            // Either is fine, but in strict mode not both in the same chart.
+            // The first one used is the one for the whole chart.
+            Indentation::SpacesOrTabs => !options.strict || *cursor.indent_char.get_or_insert(c) == c,
+        };
+
+        if allowed {
+            None
+        } else {
+            Some(i)
+        }
+    });
+
+    if let Some(bad_offset) = bad_offset {
+        let message = match options.indentation {
+            Indentation::SpacesOnly => "indentation has a tab in it, indent with spaces only",
+            Indentation::TabsOnly => "indentation has a space in it, indent with tabs only",
+            // How wide a tab is depends on the editor, so the same chart
+            // would nest differently for different people
+            Indentation::SpacesOrTabs => "indentation mixes tabs and spaces",
+        };
+        errors.push(indentation_error(
+            line,
+            bad_offset,
+            TokenType::Indent,
+            message.to_string(),
+        ));
+    }
+
+    let indent_stack = &mut cursor.indent_stack;
+    let prev_indent_level = indent_stack.last().copied().unwrap_or(0);
+
+    if current_indent_level > prev_indent_level {
+        // All the indents in a chart should be the same width. Otherwise a
+        // line indented a bit too much or too little easily ends up in a
+        // different state than it looks like it's in.
+        let width = current_indent_level - prev_indent_level;
+        match cursor.indent_width {
+            None => cursor.indent_width = Some(width),
+            Some(expected_width) if expected_width != width => errors.push(indentation_error(
+                line,
+                offset,
+                TokenType::Indent,
+                format!(
+                    "indented by {} columns, but the lines above are indented by {}",
+                    width, expected_width
+                ),
+            )),
+            _ => {}
+        }
+
+        indent_stack.push(current_indent_level);
+        tokens.push(line.token(offset, offset, TokenType::Indent));
+    } else if current_indent_level < prev_indent_level {
+        // keep popping indentation levels from the indent stack until we
+        // reach the current indent level. Every level popped is a dedent.
+        // Going back to the start of the line closes all of them.
+        while let Some(&indent_level) = indent_stack.last() {
+            if indent_level <= current_indent_level {
+                break;
             }
+            indent_stack.pop();
+            tokens.push(line.token(offset, offset, TokenType::Dedent));
+        }
+
+        // any dedent/outdent must match some previous indentation level.
+        // Otherwise it's a syntax error. The line goes with the state it's
+        // indented past.
+        if indent_stack.last().copied().unwrap_or(0) != current_indent_level {
+            errors.push(indentation_error(
+                line,
+                offset,
+                TokenType::Dedent,
+                "unindent does not match any outer indentation level".to_string(),
+            ));
         }
     }
 
     (offset, tokens)
 }
 
-fn get_token<'a>(line_number: usize, col: usize, typ: TokenType<'a>) -> Token<'a> {
-    Token {
-        pos: Position { line_number, col },
-        typ,
+fn indentation_error<'a>(line: &Line<'a>, offset: usize, found: TokenType<'a>, message: String) -> ParseError<'a> {
+    let mut error = ParseError::unexpected(line.position(offset), Some(found), vec![]);
+    error.message = message;
+    error
+}
+
+pub(super) const BOM: char = '\u{FEFF}';
+
+// rubber-duck items 7 and 8. The Lexer hands out tokens one at a time and
+// only reads as much of the input as it needs to. Every token still carries
+// its own position, so the parser doesn't have to ask the lexer where it is.
+// It works a line at a time: when the tokens of the current line run out, it
+// finds the next line and lexes the whole line.
+#[derive(Debug, Clone)]
+pub struct Lexer<'a> {
+    input: &'a str,
+    options: ParserOptions,
+    cursor: Cursor<'a>,
+    errors: Vec<ParseError<'a>>,
+}
+
+// Everything the lexer needs to carry on from where it is
+#[derive(Debug, Clone)]
+struct Cursor<'a> {
+    // byte offset of the next line to lex. None once the last line is done.
+    line_start: Option<usize>,
+    line_number: usize,
+    indent_stack: Vec<usize>,
+    // the width of the first indent in the input, which all the others
+    // should match
+    indent_width: Option<usize>,
+    // whether the first indented line used a space or a tab
+    indent_char: Option<char>,
+    // tokens of the current line that haven't been handed out yet
+    pending: VecDeque<Token<'a>>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Lexer::with_options(input, ParserOptions::default())
+    }
+
+    pub fn with_options(input: &'a str, options: ParserOptions) -> Self {
+        Lexer {
+            input,
+            options,
+            cursor: Cursor {
+                // Some editors start files with a byte order mark. It's not
+                // part of the first line, but offsets still count it so they
+                // point into the original input.
+                line_start: Some(if input.starts_with(BOM) { BOM.len_utf8() } else { 0 }),
+                line_number: 0,
+                indent_stack: Vec::new(),
+                indent_width: None,
+                indent_char: None,
+                pending: VecDeque::new(),
+            },
+            errors: Vec::new(),
+        }
+    }
+
+    // Problems with the indentation of the lines lexed so far. The lexer
+    // still makes indents and dedents for those lines, so it's up to whoever
+    // is using the tokens whether to carry on.
+    pub fn errors(&self) -> &[ParseError<'a>] {
+        &self.errors
+    }
+
+    // Lexes the next line into pending. Returns false when there are no lines
+    // left.
+    fn lex_line(&mut self) -> bool {
+        let line_start = match self.cursor.line_start {
+            Some(line_start) => line_start,
+            None => return false,
+        };
+
+        let rest = &self.input[line_start..];
+        // Lines can end with \n, \r\n (windows) or just \r (old macs). The
+        // line break is not part of the line.
+        let text = match rest.find(['\n', '\r']) {
+            Some(end) => {
+                let break_len = if rest[end..].starts_with("\r\n") { 2 } else { 1 };
+                self.cursor.line_start = Some(line_start + end + break_len);
+                &rest[..end]
+            }
+            None => {
+                self.cursor.line_start = None;
+                rest
+            }
+        };
+
+        let line = Line::new(text, self.cursor.line_number, line_start);
+        self.cursor.line_number += 1;
+
+        line_tokens(&line, &mut self.cursor, &mut self.errors, &self.options);
+
+        if self.cursor.line_start.is_none() {
+            // pop out all the Dedents. They go at the end of the last line, so
+            // that errors about them point somewhere inside the input.
+            while self.cursor.indent_stack.pop().is_some() {
+                self.cursor
+                    .pending
+                    .push_back(line.token(text.len(), text.len(), TokenType::Dedent));
+            }
+        }
+
+        true
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            if let Some(token) = self.cursor.pending.pop_front() {
+                return Some(token);
+            }
+
+            if !self.lex_line() {
+                return None;
+            }
+        }
     }
 }
 
-pub fn tokenize(input: &str) -> Vec<Token> {
-    // How to write a comment in rust. Like we do in javascript.
-    // Rust comments are more than comments though. We can write whole tests
-    // inside a comment for a function.
-
-    // How to split a string into lines? using split function. But it's not
-    // that simple. `split` return an iterator and if we want the lines as
-    // as vector or array, we have to use the collect method of the iterator
-    // The syntax for collect gets weird when we want to tell it the type to
-    // be returned.
-    // let lines =  input.split("\n").collect<Vec<&str>>();
-    // Or we can annotate the variable to which the value of
-    // we can avoid specifying it in that weird way in collect by specifying
-    // type of lines
-    let lines: Vec<&str> = input.split("\n").collect();
-    // How to create an empty vector?
-    let mut tokens: Vec<Token> = Vec::new();
-    // line and col keep track of the current line and col number
-    let mut line_number = 0;
-    // offset keeps track of the current character position in the line
-    let mut offset;
-    let mut indent_stack: Vec<usize> = Vec::new();
-
-    // TODO: can we write it as input.split("\n").map().flatten().collect()?
-    // The map function returns the list of tokens in one line
-
-    // writing `for line in lines` would mean moving lines inside the for block
-    // and hence not being available outside it
-    for line in &lines {
-        // how to convert a string into a list of characters? Use chars method
-        // on string. Again, chars returns an iterator instead of a vector.
-        // This seems to be a common pattern. Whenever a javascript programmer
-        // expects an array of something, rust functions/methods return an
-        // iterator.
-        // Probably my tokenize function should also return an iterator of
-        // Tokens instead of a Vector of tokens
-        let char_vec: Vec<char> = line.chars().collect();
-
-        let (new_offset, indent_tokens) =
-            indent_dedent_tokens(line_number, &mut indent_stack, &char_vec);
-        offset = new_offset;
-
-        // extend extends a collection with contents of an iterator
-        tokens.extend(indent_tokens);
-
-        // why can we split the char_vec at offset and then iterate on the line
-        // from that point?
-        // Because on every loop the offset changes by more than or equal to 1
-        while offset < char_vec.len() {
-            let c = char_vec[offset];
-            match c {
-                // How to create new values of a struct?
-                '%' => {
-                    tokens.push(comment_token(line_number, offset, line));
-                    break;
-                }
-                '&' => {
-                    tokens.push(get_token(line_number, offset, TokenType::ParallelState));
-                    offset += 1;
-                }
-                '$' => {
-                    tokens.push(get_token(line_number, offset, TokenType::FinalState));
-                    offset += 1;
-                }
-                '*' => {
-                    tokens.push(get_token(line_number, offset, TokenType::InitialState));
-                    offset += 1;
-                }
-                ';' => {
-                    let (new_offset, condition) = condition_token(line_number, offset, line);
-                    offset = new_offset;
-                    tokens.push(condition);
-                }
-                '-' => {
-                    if offset < line.len() - 1 && char_vec[offset + 1] == '>' {
-                        tokens.push(get_token(line_number, offset, TokenType::TransitionArrow));
-                        offset += 2;
-                    } else {
-                        tokens.push(get_token(
-                            line_number,
-                            offset,
-                            TokenType::Unknown("unknown"),
-                        ));
-                        offset += 1;
-                    }
-                }
-                '>' => {
-                    let (new_offset, condition) = action_token(line_number, offset, line);
-                    offset = new_offset;
-                    tokens.push(condition);
+fn line_tokens<'a>(
+    line: &Line<'a>,
+    cursor: &mut Cursor<'a>,
+    errors: &mut Vec<ParseError<'a>>,
+    options: &ParserOptions,
+) {
+    let (mut offset, indent_tokens) = indent_dedent_tokens(line, cursor, errors, options);
+    // where the first token of the line is
+    let line_start = offset;
+    let tokens = &mut cursor.pending;
+
+    // extend extends a collection with contents of an iterator
+    tokens.extend(indent_tokens);
+
+    // Every arm below moves offset forward by at least one character,
+    // and always to the start of a character
+    while let Some(c) = line.char_at(offset) {
+        match c {
+            // How to create new values of a struct?
+            '%' => {
+                tokens.push_back(comment_token(line, offset));
+                break;
+            }
+            '&' => {
+                tokens.push_back(line.token(offset, offset + 1, TokenType::ParallelState));
+                offset += 1;
+            }
+            '$' => {
+                tokens.push_back(line.token(offset, offset + 1, TokenType::FinalState));
+                offset += 1;
+            }
+            '*' => {
+                tokens.push_back(line.token(offset, offset + 1, TokenType::InitialState));
+                offset += 1;
+            }
+            ';' => offset = guard_tokens(line, offset, tokens),
+            '-' if line.char_at(offset + 1) == Some('>') && line.char_at(offset + 2) == Some('>') => {
+                tokens.push_back(line.token(offset, offset + 3, TokenType::InternalTransitionArrow));
+                offset += 3;
+            }
+            '-' if line.char_at(offset + 1) == Some('>') => {
+                tokens.push_back(line.token(offset, offset + 2, TokenType::TransitionArrow));
+                offset += 2;
+            }
+            '>' => offset = action_tokens(line, offset, tokens),
+            c if is_identifier_start(c) => {
+                let text = identifier_text(line, offset);
+
+                if let Some((end, history)) = history_marker(line, line_start, offset, text) {
+                    tokens.push_back(line.token(offset, end, history));
+                    offset = end;
+                    continue;
                 }
-                c if is_identifier_start(c) => {
-                    let identifier = identifier_token(line_number, offset, line);
-                    let text = match identifier.typ {
-                        TokenType::Identifier(t) => t,
-                        _ => " ",
-                    };
+
+                // whatever comes after `after` is the delay. The parser
+                // makes sense of it.
+                if tokens.back().map(|t| &t.typ) == Some(&TokenType::After) {
+                    tokens.push_back(line.token(offset, offset + text.len(), TokenType::Delay(text)));
                     offset += text.len();
-                    tokens.push(identifier);
-                }
-                c if c.is_whitespace() => offset += 1,
-                _ => {
-                    tokens.push(get_token(
-                        line_number,
-                        offset,
-                        TokenType::Unknown("unknown"),
-                    ));
-                    offset += 1;
+                    continue;
                 }
+
+                let typ = line_keyword(line, line_start, offset, text).unwrap_or(TokenType::Identifier(text));
+                tokens.push_back(line.token(offset, offset + text.len(), typ));
+                offset += text.len();
+            }
+            c if c.is_whitespace() => offset += c.len_utf8(),
+            c => {
+                tokens.push_back(line.token(offset, offset + c.len_utf8(), TokenType::Unknown("unknown")));
+                offset += c.len_utf8();
             }
         }
-
-        line_number += 1;
-    }
-
-    // pop out all the Dedents
-    while indent_stack.len() > 0 {
-        indent_stack.pop();
-        tokens.push(get_token(line_number, 0, TokenType::Dedent))
     }
+}
 
-    // println!("tokens: {:?}", tokens.len());
-    tokens
+// All the tokens at once, for when the whole input is needed anyway.
+// rust tip: collect works on any iterator, and the return type tells it what
+// to collect into.
+pub fn tokenize(input: &str) -> Vec<Token<'_>> {
+    Lexer::new(input).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     static INPUT: &str = "abc
 % some comment
@@ -382,8 +883,10 @@ mod tests {
             TokenType::Identifier("that"),
             TokenType::Action("andDoThis"),
             TokenType::Identifier("lastState"),
-            TokenType::Indent,
+            // a line with only a comment doesn't indent, the line after it
+            // does
             TokenType::Comment("% trying out transient state"),
+            TokenType::Indent,
             TokenType::TransitionArrow,
             TokenType::Identifier("ast"),
             TokenType::Condition("ifyes"),
@@ -407,4 +910,434 @@ mod tests {
             i += 1;
         }
     }
+
+    #[test]
+    fn test_malformed_lines() {
+        // blank and whitespace only lines are skipped
+        assert_eq!(Vec::<Token>::new(), tokenize(""));
+        assert_eq!(Vec::<Token>::new(), tokenize("   \n\n  "));
+
+        let types = |input| -> Vec<TokenType> { tokenize(input).into_iter().map(|t| t.typ).collect() };
+
+        // markers without a name after them
+        assert_eq!(
+            vec![
+                TokenType::Identifier("a"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("b"),
+                TokenType::Unknown("unknown"),
+            ],
+            types("a -> b;")
+        );
+        assert_eq!(vec![TokenType::Identifier("a"), TokenType::Unknown("unknown")], types("a >  "));
+        assert_eq!(vec![TokenType::Identifier("a"), TokenType::Unknown("unknown")], types("a -"));
+
+        assert_eq!(
+            vec![
+                TokenType::Identifier("a"),
+                TokenType::Unknown("unknown"),
+                TokenType::Unknown("unknown"),
+            ],
+            types("a ¬ -")
+        );
+    }
+
+    #[test]
+    fn test_unicode() {
+        let tokens = tokenize("größe\n  klick -> 待機 % grüß dich\n  待機*");
+        let expected = vec![
+            (TokenType::Identifier("größe"), 0, 0, 0),
+            (TokenType::Indent, 1, 2, 10),
+            (TokenType::Identifier("klick"), 1, 2, 10),
+            (TokenType::TransitionArrow, 1, 8, 16),
+            (TokenType::Identifier("待機"), 1, 11, 19),
+            (TokenType::Comment("% grüß dich"), 1, 14, 26),
+            (TokenType::Identifier("待機"), 2, 2, 42),
+            (TokenType::InitialState, 2, 4, 48),
+            (TokenType::Dedent, 2, 5, 49),
+        ];
+
+        let actual: Vec<(TokenType, usize, usize, usize)> = tokens
+            .into_iter()
+            .map(|t| (t.typ, t.pos.line_number, t.pos.col, t.pos.offset))
+            .collect();
+        assert_eq!(expected, actual);
+
+        // a combining accent is part of the name
+        let types: Vec<TokenType> = tokenize("cafe\u{301} -> x").into_iter().map(|t| t.typ).collect();
+        assert_eq!(TokenType::Identifier("cafe\u{301}"), types[0]);
+    }
+
+    #[test]
+    fn test_indentation_errors() {
+        let errors_with = |input, options| -> Vec<(usize, usize, String)> {
+            let mut lexer = Lexer::with_options(input, options);
+            lexer.by_ref().count();
+            lexer
+                .errors()
+                .iter()
+                .map(|e| (e.pos.line_number, e.pos.col, e.message.clone()))
+                .collect()
+        };
+        let errors = |input| errors_with(input, ParserOptions::default());
+
+        assert!(errors(INPUT).is_empty());
+        assert_eq!(
+            vec![(3, 6, "unindent does not match any outer indentation level".to_string())],
+            errors("abc\n    def\n        ghi\n      jkl")
+        );
+        assert_eq!(
+            vec![(2, 6, "indented by 4 columns, but the lines above are indented by 2".to_string())],
+            errors("abc\n  def\n      ghi\n  jkl")
+        );
+
+        // tabs go to the next tab stop
+        assert!(errors("abc\n\tdef\n\t\tghi\n    \tjkl\n\tmno").is_empty());
+
+        let options = |indentation, strict| ParserOptions {
+            indentation,
+            tab_width: 4,
+            strict,
+        };
+        assert_eq!(
+            vec![(1, 2, "indentation has a tab in it, indent with spaces only".to_string())],
+            errors_with("abc\n  \tdef", options(Indentation::SpacesOnly, false))
+        );
+        assert_eq!(
+            vec![(2, 1, "indentation has a space in it, indent with tabs only".to_string())],
+            errors_with("abc\n\tdef\n\t    ghi", options(Indentation::TabsOnly, false))
+        );
+        assert!(errors_with("abc\n\tdef\n    ghi", options(Indentation::SpacesOrTabs, false)).is_empty());
+        assert_eq!(
+            vec![(2, 0, "indentation mixes tabs and spaces".to_string())],
+            errors_with("abc\n\tdef\n    ghi", options(Indentation::SpacesOrTabs, true))
+        );
+
+        // the tokens are still the best guess at what was meant
+        let types: Vec<TokenType> = tokenize("abc\n    def\n        ghi\n      jkl")
+            .into_iter()
+            .map(|t| t.typ)
+            .collect();
+        assert_eq!(
+            vec![
+                TokenType::Identifier("abc"),
+                TokenType::Indent,
+                TokenType::Identifier("def"),
+                TokenType::Indent,
+                TokenType::Identifier("ghi"),
+                TokenType::Dedent,
+                TokenType::Identifier("jkl"),
+                TokenType::Dedent,
+            ],
+            types
+        );
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let tokens = |input| -> Vec<(TokenType, usize, usize, usize)> {
+            tokenize(input)
+                .into_iter()
+                .map(|t| (t.typ, t.pos.line_number, t.pos.col, t.pos.offset))
+                .collect()
+        };
+        let expected = vec![
+            (TokenType::Identifier("abc"), 0, 0, 0),
+            (TokenType::Indent, 1, 2, 7),
+            (TokenType::Identifier("def"), 1, 2, 7),
+            (TokenType::Comment("% done"), 1, 6, 11),
+            (TokenType::Dedent, 2, 0, 19),
+        ];
+
+        assert_eq!(expected, tokens("abc\r\n  def % done\r\n"));
+
+        // a lone \r is one byte shorter than \r\n
+        let expected: Vec<(TokenType, usize, usize, usize)> = expected
+            .into_iter()
+            .map(|(typ, line, col, offset)| (typ, line, col, offset - line))
+            .collect();
+        assert_eq!(expected, tokens("abc\r  def % done\r"));
+
+        // the byte order mark takes up 3 bytes, but no columns
+        let tokens = tokens("\u{FEFF}abc\n  def");
+        assert_eq!((TokenType::Identifier("abc"), 0, 0, 3), tokens[0]);
+        assert_eq!((TokenType::Identifier("def"), 1, 2, 9), tokens[2]);
+    }
+
+    #[test]
+    fn test_entry_exit() {
+        let types = |input| -> Vec<TokenType> { tokenize(input).into_iter().map(|t| t.typ).collect() };
+
+        assert_eq!(
+            vec![
+                TokenType::Entry,
+                TokenType::Action("startTimer"),
+                TokenType::Action("log"),
+                TokenType::Exit,
+                TokenType::Action("stopTimer"),
+            ],
+            types("entry > startTimer > log\nexit >stopTimer")
+        );
+
+        // anywhere else they are just names
+        assert_eq!(
+            vec![
+                TokenType::Identifier("exit"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("entry"),
+                TokenType::Action("go"),
+            ],
+            types("exit -> entry > go")
+        );
+
+        assert_eq!(
+            vec![
+                TokenType::Invoke,
+                TokenType::Identifier("fetchUser"),
+                TokenType::Activity,
+                TokenType::Action("beep"),
+                TokenType::Identifier("invoke"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("activity"),
+            ],
+            types("invoke fetchUser\nactivity > beep\ninvoke -> activity")
+        );
+    }
+
+    #[test]
+    fn test_after() {
+        let types = |input| -> Vec<TokenType> { tokenize(input).into_iter().map(|t| t.typ).collect() };
+
+        assert_eq!(
+            vec![
+                TokenType::After,
+                TokenType::Delay("3000ms"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("idle"),
+                TokenType::After,
+                TokenType::Delay("1.5s"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("timeout"),
+                TokenType::Condition("stillWaiting"),
+            ],
+            types("after 3000ms -> idle\nafter 1.5s -> timeout; stillWaiting")
+        );
+
+        // without a number after it, it's a name
+        assert_eq!(
+            vec![
+                TokenType::Identifier("after"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("after"),
+            ],
+            types("after -> after")
+        );
+
+        // any word after it is the delay, whether it makes sense or not, but
+        // a history state can still be called `after`
+        assert_eq!(
+            vec![
+                TokenType::After,
+                TokenType::Delay(".s"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("b"),
+                TokenType::Identifier("after"),
+                TokenType::History(HistoryMode::Deep),
+            ],
+            types("after .s -> b\nafter H*")
+        );
+    }
+
+    #[test]
+    fn test_guards() {
+        let tokens = tokenize("a -> b; isValid and not(isLocked or isBusy) > go");
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.typ.clone()).collect();
+
+        assert_eq!(
+            vec![
+                TokenType::Identifier("a"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("b"),
+                TokenType::Condition("isValid"),
+                TokenType::And,
+                TokenType::Not,
+                TokenType::OpenParen,
+                TokenType::Condition("isLocked"),
+                TokenType::Or,
+                TokenType::Condition("isBusy"),
+                TokenType::CloseParen,
+                TokenType::Action("go"),
+            ],
+            types
+        );
+        // the first one has the `;`, the rest are just their text
+        assert_eq!(Span { start: 6, end: 15 }, tokens[3].span);
+        assert_eq!(Span { start: 16, end: 19 }, tokens[4].span);
+
+        let types: Vec<TokenType> = tokenize("-> b;(x)").into_iter().map(|t| t.typ).collect();
+        assert_eq!(TokenType::OpenParen, types[2]);
+        let types: Vec<TokenType> = tokenize("-> b; > go").into_iter().map(|t| t.typ).collect();
+        assert_eq!(TokenType::Unknown("unknown"), types[2]);
+    }
+
+    #[test]
+    fn test_arguments() {
+        let types = |input| -> Vec<TokenType> { tokenize(input).into_iter().map(|t| t.typ).collect() };
+
+        assert_eq!(
+            vec![
+                TokenType::Identifier("a"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("b"),
+                TokenType::Condition("hasRole"),
+                TokenType::OpenParen,
+                TokenType::Identifier("admin"),
+                TokenType::CloseParen,
+                TokenType::And,
+                TokenType::OpenParen,
+                TokenType::Condition("x"),
+                TokenType::CloseParen,
+                TokenType::Action("log"),
+                TokenType::OpenParen,
+                TokenType::String("entered, or not"),
+                TokenType::Comma,
+                TokenType::Identifier("level"),
+                TokenType::Equals,
+                TokenType::Number("-2.5"),
+                TokenType::Comma,
+                TokenType::Identifier("loud"),
+                TokenType::Equals,
+                TokenType::Identifier("true"),
+                TokenType::CloseParen,
+                TokenType::Action("save"),
+            ],
+            types("a -> b; hasRole(admin) and (x) > log(\"entered, or not\", level=-2.5, loud=true) > save")
+        );
+
+        // an unterminated string is left for the main loop
+        assert_eq!(
+            vec![
+                TokenType::Action("log"),
+                TokenType::OpenParen,
+                TokenType::Unknown("unknown"),
+                TokenType::Identifier("oops"),
+            ],
+            types("> log(\"oops")
+        );
+    }
+
+    #[test]
+    fn test_action_lists() {
+        let tokens = tokenize("click > validate, log(1, 2) ,save");
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.typ.clone()).collect();
+
+        assert_eq!(
+            vec![
+                TokenType::Identifier("click"),
+                TokenType::Action("validate"),
+                TokenType::Action("log"),
+                TokenType::OpenParen,
+                TokenType::Number("1"),
+                TokenType::Comma,
+                TokenType::Number("2"),
+                TokenType::CloseParen,
+                TokenType::Action("save"),
+            ],
+            types
+        );
+        assert_eq!(Span { start: 16, end: 21 }, tokens[2].span);
+        assert_eq!(Span { start: 28, end: 33 }, tokens[8].span);
+
+        let types: Vec<TokenType> = tokenize("a > b,").into_iter().map(|t| t.typ).collect();
+        assert_eq!(TokenType::Unknown("unknown"), types[2]);
+    }
+
+    #[test]
+    fn test_internal_arrow() {
+        let tokens = tokenize("a ->> b\nc -> > d");
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.typ.clone()).collect();
+
+        assert_eq!(
+            vec![
+                TokenType::Identifier("a"),
+                TokenType::InternalTransitionArrow,
+                TokenType::Identifier("b"),
+                TokenType::Identifier("c"),
+                TokenType::TransitionArrow,
+                TokenType::Action("d"),
+            ],
+            types
+        );
+        assert_eq!(Span { start: 2, end: 5 }, tokens[1].span);
+    }
+
+    #[test]
+    fn test_history_markers() {
+        let types = |input| -> Vec<TokenType> { tokenize(input).into_iter().map(|t| t.typ).collect() };
+
+        assert_eq!(
+            vec![
+                TokenType::Identifier("resume"),
+                TokenType::History(HistoryMode::Shallow),
+                TokenType::Identifier("deep"),
+                TokenType::History(HistoryMode::Deep),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("step1"),
+            ],
+            types("resume H\ndeep  H* -> step1")
+        );
+
+        // anywhere else H is just a name
+        assert_eq!(
+            vec![
+                TokenType::Identifier("H"),
+                TokenType::TransitionArrow,
+                TokenType::Identifier("H"),
+                TokenType::Identifier("stepH"),
+                TokenType::InitialState,
+            ],
+            types("H -> H\nstepH*")
+        );
+
+        assert_eq!(
+            vec![
+                TokenType::Identifier("resume"),
+                TokenType::InitialState,
+                TokenType::History(HistoryMode::Shallow),
+            ],
+            types("resume* H")
+        );
+    }
+
+    #[test]
+    fn test_lexer() {
+        let mut lexer = Lexer::new(INPUT);
+
+        // the first line and a half
+        let first: Vec<Token> = lexer.by_ref().take(4).collect();
+        assert_eq!(TokenType::Identifier("def"), first[3].typ);
+
+        let rest: Vec<Token> = lexer.by_ref().collect();
+        assert_eq!(None, lexer.next());
+        assert_eq!(tokenize(INPUT)[4..].to_vec(), rest);
+
+        // errors show up as the lines with them are lexed
+        let mut lexer = Lexer::new("abc\n  def\n   ghi\n jkl");
+        let _ = lexer.by_ref().take(3).count();
+        assert!(lexer.errors().is_empty());
+        let _ = lexer.by_ref().count();
+        assert_eq!(2, lexer.errors().len());
+    }
+
+    proptest! {
+        #[test]
+        fn tokenize_never_panics(input in "\\PC*") {
+            tokenize(&input);
+        }
+
+        #[test]
+        fn tokenize_never_panics_on_statecharts(input in "([a-zé#._ ]{0,8}[-;>&$*%]{0,2}[ \t\r\n]{0,3}){0,20}") {
+            tokenize(&input);
+        }
+    }
 }
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+
+use crate::parser::{Span, StateNode, StateType, TransitionNode};
+
+// Works out which state every transition goes to. Targets are written the
+// way xstate wants them, and are looked up the way xstate looks them up:
+//
+// - `sibling` or `sibling.child` starts from the parent of the state the
+//   transition is on
+// - `.child` or `.child.grandchild` starts from the state itself
+// - `#id` is the state with that id, and `#id.child.grandchild` walks down
+//   from there. Every state has an id. It's `name` for states written as
+//   `#name` and the state's canonical path otherwise.
+//
+// The canonical path of a state is the keys of the states from the root down
+// to it, joined by the delimiter. E.g. `abc.ast.nestedstate2`. The root
+// state's name is the id of the machine, so it's where every path starts.
+//
+// Inside this module paths are lists of the keys in StateNode::states,
+// starting with the root state's name. E.g. ["abc", "ast", "nestedstate2"].
+
+// Which of a state's lists of transitions a transition is in
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TransitionList {
+    // StateNode::on
+    On,
+    // on_done and on_error of the invoke at this index in StateNode::invoke
+    OnDone(usize),
+    OnError(usize),
+    // The target of a history state, for when there's no history yet. It's
+    // looked up the same way as the target of a transition on the history
+    // state would be. There's only one, so the index is always 0.
+    History,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ResolvedTransition<'a> {
+    // path of the state the transition is on
+    pub source: Vec<&'a str>,
+    pub list: TransitionList,
+    // which of the transitions in the list it is
+    pub index: usize,
+    // path of the state the transition goes to
+    pub target: Vec<&'a str>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UnresolvedTarget<'a> {
+    pub source: Vec<&'a str>,
+    pub list: TransitionList,
+    pub index: usize,
+    pub target: &'a str,
+    // where the target is in the input
+    pub span: Span,
+    pub message: String,
+}
+
+// A parsed chart along with the addresses of all its states. Exporters,
+// editors and the resolution pass all find states the same way through it.
+pub struct Chart<'s, 'a> {
+    root: &'s StateNode<'a>,
+    delimiter: String,
+    // paths of all the states, parents before their children
+    paths: Vec<Vec<&'a str>>,
+    ids: HashMap<String, Vec<&'a str>>,
+}
+
+impl<'s, 'a> Chart<'s, 'a> {
+    pub fn new(root: &'s StateNode<'a>) -> Self {
+        Chart::with_delimiter(root, ".")
+    }
+
+    // xstate lets machines use something other than `.` between the keys of
+    // a path. The delimiter is used for ids, targets and canonical paths.
+    pub fn with_delimiter(root: &'s StateNode<'a>, delimiter: &str) -> Self {
+        let mut chart = Chart {
+            root,
+            delimiter: delimiter.to_string(),
+            paths: vec![],
+            ids: HashMap::new(),
+        };
+
+        collect_paths(root, &mut vec![], &mut chart.paths);
+        for path in &chart.paths {
+            let id = match chart.state_at(path).explicit_id() {
+                Some(id) => id.to_string(),
+                None => chart.canonical_path(path),
+            };
+            chart.ids.insert(id, path.clone());
+        }
+
+        chart
+    }
+
+    // E.g. ["abc", "#pasta", "child"] -> "abc.pasta.child"
+    pub fn canonical_path(&self, path: &[&str]) -> String {
+        let keys: Vec<&str> = path.iter().map(|key| key_text(key)).collect();
+        keys.join(&self.delimiter)
+    }
+
+    // Every state with its canonical path, parents before their children
+    pub fn states(&self) -> Vec<(String, &'s StateNode<'a>)> {
+        self.paths
+            .iter()
+            .map(|path| (self.canonical_path(path), self.state_at(path)))
+            .collect()
+    }
+
+    // Finds a state by its canonical path, e.g. `abc.ast.nestedstate2`
+    pub fn find_state(&self, path: &str) -> Option<&'s StateNode<'a>> {
+        let mut keys = path.split(self.delimiter.as_str());
+
+        if keys.next() != Some(self.root.key()) {
+            return None;
+        }
+
+        let rest: Vec<&str> = keys.collect();
+        let path = self.walk(vec![self.root.id], &rest.join(&self.delimiter)).ok()?;
+        Some(self.state_at(&path))
+    }
+
+    // Finds a state by id, with or without the `#`. Like in targets, the id
+    // can be followed by a path, e.g. `#abc.lastState`.
+    pub fn find_by_id(&self, id: &str) -> Option<&'s StateNode<'a>> {
+        let id = id.strip_prefix('#').unwrap_or(id);
+        let path = self.path_of_id(id).ok()?;
+        Some(self.state_at(&path))
+    }
+
+    // Returns the transitions which go somewhere, and the ones which don't.
+    // Both go state by state, parents before their children. The targets of
+    // history states are in there too, see TransitionList::History.
+    pub fn resolve_targets(&self) -> (Vec<ResolvedTransition<'a>>, Vec<UnresolvedTarget<'a>>) {
+        let mut resolved = vec![];
+        let mut unresolved = vec![];
+
+        for source in &self.paths {
+            let state = self.state_at(source);
+
+            let history_target = match state.typ {
+                StateType::History { target, .. } => target.zip(state.spans.history_target),
+                _ => None,
+            };
+
+            if let Some((target, span)) = history_target {
+                match self.resolve_target(source, target) {
+                    Ok(path) => resolved.push(ResolvedTransition {
+                        source: source.clone(),
+                        list: TransitionList::History,
+                        index: 0,
+                        target: path,
+                    }),
+                    Err(message) => unresolved.push(UnresolvedTarget {
+                        source: source.clone(),
+                        list: TransitionList::History,
+                        index: 0,
+                        target,
+                        span,
+                        message,
+                    }),
+                }
+            }
+
+            for (list, transitions) in transition_lists(state) {
+                for (index, transition) in transitions.iter().enumerate() {
+                    // targetless transitions stay where they are
+                    let span = match transition.spans.target {
+                        Some(span) => span,
+                        None => continue,
+                    };
+
+                    match self.resolve_target(source, transition.target) {
+                        Ok(target) => resolved.push(ResolvedTransition {
+                            source: source.clone(),
+                            list,
+                            index,
+                            target,
+                        }),
+                        Err(message) => unresolved.push(UnresolvedTarget {
+                            source: source.clone(),
+                            list,
+                            index,
+                            target: transition.target,
+                            span,
+                            message,
+                        }),
+                    }
+                }
+            }
+        }
+
+        (resolved, unresolved)
+    }
+
+    // The state at path. Only for paths we made, so we know it's there.
+    fn state_at(&self, path: &[&'a str]) -> &'s StateNode<'a> {
+        path[1..].iter().fold(self.root, |state, key| &state.states[key])
+    }
+
+    fn resolve_target(&self, source: &[&'a str], target: &str) -> Result<Vec<&'a str>, String> {
+        if let Some(id) = target.strip_prefix('#') {
+            return self.path_of_id(id);
+        }
+
+        if let Some(rest) = target.strip_prefix(self.delimiter.as_str()) {
+            return self.walk(source.to_vec(), rest);
+        }
+
+        match source.split_last() {
+            Some((_, parent)) if !parent.is_empty() => self.walk(parent.to_vec(), target),
+            // xstate doesn't look for siblings of the root state either
+            _ => Err(format!(
+                "`{}` is not a valid target from the root state, did you mean `{}{}`?",
+                target, self.delimiter, target
+            )),
+        }
+    }
+
+    fn path_of_id(&self, id: &str) -> Result<Vec<&'a str>, String> {
+        // ids can have the delimiter in them, so try the whole thing first
+        if let Some(path) = self.ids.get(id) {
+            return Ok(path.clone());
+        }
+
+        let (id, rest) = match id.find(self.delimiter.as_str()) {
+            Some(i) => (&id[..i], &id[i + self.delimiter.len()..]),
+            None => (id, ""),
+        };
+
+        match self.ids.get(id) {
+            Some(path) => self.walk(path.clone(), rest),
+            None => Err(format!("there is no state with the id `{}`", id)),
+        }
+    }
+
+    // Follows the keys in rest down from the state at path
+    fn walk(&self, mut path: Vec<&'a str>, rest: &str) -> Result<Vec<&'a str>, String> {
+        // xstate stops at the first empty key, e.g. the end of `#abc.`
+        for key in rest.split(self.delimiter.as_str()).take_while(|key| !key.is_empty()) {
+            let state = self.state_at(&path);
+
+            match state.states.keys().find(|k| key_text(k) == key) {
+                Some(k) => path.push(k),
+                None => return Err(format!("`{}` is not a sub state of `{}`", key, state.key())),
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+// Same as Chart::new(root).resolve_targets()
+pub fn resolve_targets<'a>(root: &StateNode<'a>) -> (Vec<ResolvedTransition<'a>>, Vec<UnresolvedTarget<'a>>) {
+    Chart::new(root).resolve_targets()
+}
+
+fn collect_paths<'a>(state: &StateNode<'a>, parent: &mut Vec<&'a str>, paths: &mut Vec<Vec<&'a str>>) {
+    parent.push(state.id);
+    paths.push(parent.clone());
+
+    for sub_state in state.states.values() {
+        collect_paths(sub_state, parent, paths);
+    }
+
+    parent.pop();
+}
+
+// All the transitions of a state, list by list. The ones in on come first,
+// then the ones of every invoke in order.
+fn transition_lists<'s, 'a>(state: &'s StateNode<'a>) -> Vec<(TransitionList, &'s [TransitionNode<'a>])> {
+    let mut lists = vec![(TransitionList::On, state.on.as_slice())];
+
+    for (i, invoke) in state.invoke.iter().enumerate() {
+        lists.push((TransitionList::OnDone(i), invoke.on_done.as_slice()));
+        lists.push((TransitionList::OnError(i), invoke.on_error.as_slice()));
+    }
+
+    lists
+}
+
+// the name without the `#` of an explicit id
+fn key_text(key: &str) -> &str {
+    key.strip_prefix('#').unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    static INPUT: &str = "abc
+  def -> .ast
+  ast&*
+    opq -> lastState; ifyes
+    uvw -> #abc.lastState
+    nestedstate1
+      next -> #pasta.child
+    nestedstate2*
+      back -> #abc.ast.nestedstate1
+  #pasta
+    child
+      typo -> oops
+      typo -> #nope
+    up -> ast.nestedstate2
+    down -> .missing
+  lastState
+    -> lastState; again";
+
+    #[test]
+    fn test_resolve_targets() {
+        let mut parser = Parser::new();
+        let ast = parser.parse(INPUT).unwrap();
+        let (resolved, unresolved) = resolve_targets(&ast);
+
+        let targets: Vec<(&str, Vec<&str>)> = resolved
+            .iter()
+            .map(|r| {
+                assert_eq!(TransitionList::On, r.list);
+                (Chart::new(&ast).state_at(&r.source).on[r.index].target, r.target.clone())
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (".ast", vec!["abc", "ast"]),
+                ("lastState", vec!["abc", "lastState"]),
+                ("#abc.lastState", vec!["abc", "lastState"]),
+                ("#pasta.child", vec!["abc", "#pasta", "child"]),
+                ("#abc.ast.nestedstate1", vec!["abc", "ast", "nestedstate1"]),
+                ("ast.nestedstate2", vec!["abc", "ast", "nestedstate2"]),
+                ("lastState", vec!["abc", "lastState"]),
+            ],
+            targets
+        );
+
+        let errors: Vec<(&str, &str, &str)> = unresolved
+            .iter()
+            .map(|u| (u.target, &INPUT[u.span.start..u.span.end], u.message.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (".missing", ".missing", "`missing` is not a sub state of `pasta`"),
+                ("oops", "oops", "`oops` is not a sub state of `pasta`"),
+                ("#nope", "#nope", "there is no state with the id `nope`"),
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_root_transitions() {
+        let mut parser = Parser::new();
+        let ast = parser.parse("abc\n  click -> def\n  count > add\n  def").unwrap();
+        let (resolved, unresolved) = resolve_targets(&ast);
+
+        // `count > add` doesn't go anywhere, so there's nothing to resolve
+        assert!(resolved.is_empty());
+        assert_eq!(1, unresolved.len());
+        assert_eq!(
+            "`def` is not a valid target from the root state, did you mean `.def`?",
+            unresolved[0].message
+        );
+    }
+
+    #[test]
+    fn test_invoke_targets() {
+        let input = "abc
+  loading
+    invoke fetchUser
+      done -> loaded
+      error -> nope
+    invoke fetchPosts
+      error -> .retrying
+    retrying
+  loaded";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let (resolved, unresolved) = resolve_targets(&ast);
+
+        let targets: Vec<(TransitionList, usize, Vec<&str>)> =
+            resolved.into_iter().map(|r| (r.list, r.index, r.target)).collect();
+        assert_eq!(
+            vec![
+                (TransitionList::OnDone(0), 0, vec!["abc", "loaded"]),
+                (TransitionList::OnError(1), 0, vec!["abc", "loading", "retrying"]),
+            ],
+            targets
+        );
+
+        assert_eq!(1, unresolved.len());
+        assert_eq!(TransitionList::OnError(0), unresolved[0].list);
+        assert_eq!("nope", &input[unresolved[0].span.start..unresolved[0].span.end]);
+        assert_eq!("`nope` is not a sub state of `abc`", unresolved[0].message);
+    }
+
+    #[test]
+    fn test_history_targets() {
+        let input = "wizard
+  step1
+    sub
+  resume H -> step1.sub
+  typo H -> zzz";
+        let mut parser = Parser::new();
+        let ast = parser.parse(input).unwrap();
+        let (resolved, unresolved) = resolve_targets(&ast);
+
+        assert_eq!(
+            vec![ResolvedTransition {
+                source: vec!["wizard", "resume"],
+                list: TransitionList::History,
+                index: 0,
+                target: vec!["wizard", "step1", "sub"],
+            }],
+            resolved
+        );
+
+        assert_eq!(1, unresolved.len());
+        assert_eq!(TransitionList::History, unresolved[0].list);
+        assert_eq!("zzz", &input[unresolved[0].span.start..unresolved[0].span.end]);
+        assert_eq!("`zzz` is not a sub state of `wizard`", unresolved[0].message);
+    }
+
+    #[test]
+    fn test_paths_and_lookups() {
+        let mut parser = Parser::new();
+        let ast = parser.parse(INPUT).unwrap();
+        let chart = Chart::new(&ast);
+
+        let paths: Vec<String> = chart.states().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            vec![
+                "abc",
+                "abc.ast",
+                "abc.ast.nestedstate1",
+                "abc.ast.nestedstate2",
+                "abc.pasta",
+                "abc.pasta.child",
+                "abc.lastState",
+            ],
+            paths
+        );
+
+        assert_eq!("nestedstate2", chart.find_state("abc.ast.nestedstate2").unwrap().id);
+        assert_eq!("#pasta", chart.find_state("abc.pasta").unwrap().id);
+        assert_eq!("abc", chart.find_state("abc").unwrap().id);
+        assert_eq!(None, chart.find_state("ast.nestedstate2"));
+        assert_eq!(None, chart.find_state("abc.nope"));
+
+        assert_eq!("lastState", chart.find_by_id("#abc.lastState").unwrap().id);
+        assert_eq!("abc", chart.find_by_id("abc").unwrap().id);
+        assert_eq!("child", chart.find_by_id("#pasta.child").unwrap().id);
+        assert_eq!(None, chart.find_by_id("#nope"));
+
+        let chart = Chart::with_delimiter(&ast, "/");
+        assert_eq!("nestedstate1", chart.find_state("abc/ast/nestedstate1").unwrap().id);
+        assert_eq!("child", chart.find_by_id("#abc/pasta/child").unwrap().id);
+        assert_eq!("abc/pasta/child", chart.canonical_path(&["abc", "#pasta", "child"]));
+    }
+}
@@ -0,0 +1,1000 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Map, Value};
+
+use crate::parser::{
+    Argument, Call, Guard, HistoryMode, InvokeNode, Literal, StateNode, StateType, TransitionNode,
+};
+use crate::resolve::{Chart, TransitionList};
+
+// Turns the StateNode tree we get from the parser into an xstate machine
+// config. The parser keeps transitions as a flat list (see the comment on
+// StateNode::on), so most of the work here is grouping them back by event.
+//
+// The root state becomes the machine and its name becomes the machine id.
+// That's what makes targets like `#abc.lastState` work in xstate.
+
+// How the transitions of an event are written out.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TransitionFormat {
+    // `{ on: { click: "target" } }`. A single transition without a condition
+    // or actions collapses to its target, anything else becomes an object and
+    // repeated events become an array.
+    Compact,
+    // A transition object per transition, an array only for repeated events.
+    Object,
+    // Every event maps to an array of transition objects, even when there is
+    // only one.
+    Array,
+}
+
+// The xstate major version the config is written for.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Version {
+    // Conditions go in `cond`, transient transitions under the `""` event
+    // and activities in `activities`.
+    V4,
+    // Conditions go in `guard`, transient transitions under `always`.
+    // Activities are gone in v5, so they are invoked like services.
+    V5,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ExportOptions {
+    pub transitions: TransitionFormat,
+    pub version: Version,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            transitions: TransitionFormat::Object,
+            version: Version::V5,
+        }
+    }
+}
+
+// Converts a parsed statechart into an xstate machine config.
+pub fn to_xstate(root: &StateNode) -> Value {
+    to_xstate_with_options(root, &ExportOptions::default())
+}
+
+pub fn to_xstate_with_options(root: &StateNode, options: &ExportOptions) -> Value {
+    let export = Export::new(root, options);
+    let mut machine = Map::new();
+    // the root state's name is the machine id, whether it's written as `#abc`
+    // or as `abc`
+    machine.insert("id".to_string(), Value::from(root.key()));
+    machine.extend(state_config(root, &mut vec![], &export));
+
+    Value::Object(machine)
+}
+
+// What state_config needs to know besides the state itself
+struct Export<'e, 'a> {
+    options: &'e ExportOptions,
+    // The transitions which go to the state they start in, or to a state
+    // inside it. By the path of the state they start in (the same paths as
+    // in resolve), and which list they are in and where.
+    inward: HashMap<Vec<&'a str>, Vec<(TransitionList, usize)>>,
+}
+
+impl<'e, 'a> Export<'e, 'a> {
+    fn new(root: &StateNode<'a>, options: &'e ExportOptions) -> Self {
+        let mut inward: HashMap<Vec<&'a str>, Vec<(TransitionList, usize)>> = HashMap::new();
+
+        // only v5 needs to know, see transition_config
+        if options.version == Version::V5 {
+            let (resolved, _) = Chart::new(root).resolve_targets();
+
+            for transition in resolved {
+                // a history state's target isn't a transition, so it
+                // doesn't re-enter anything
+                if transition.list != TransitionList::History
+                    && transition.target.starts_with(&transition.source)
+                {
+                    inward
+                        .entry(transition.source)
+                        .or_default()
+                        .push((transition.list, transition.index));
+                }
+            }
+        }
+
+        Export { options, inward }
+    }
+
+    // Whether the transition at index in list of the state at path leaves
+    // the state and enters it again. Only `->` does, `->>` stays inside.
+    fn reenters(
+        &self,
+        path: &[&'a str],
+        list: TransitionList,
+        index: usize,
+        transition: &TransitionNode,
+    ) -> bool {
+        !transition.internal
+            && self
+                .inward
+                .get(path)
+                .is_some_and(|transitions| transitions.contains(&(list, index)))
+    }
+}
+
+// Same as `to_xstate`, but pretty printed so it can be written to a file.
+pub fn to_xstate_string(root: &StateNode) -> String {
+    to_xstate_string_with_options(root, &ExportOptions::default())
+}
+
+pub fn to_xstate_string_with_options(root: &StateNode, options: &ExportOptions) -> String {
+    // rust tip: `{:#}` is the alternate flag. serde_json uses it to pretty
+    // print a Value.
+    format!("{:#}", to_xstate_with_options(root, options))
+}
+
+fn state_config<'a>(
+    state: &StateNode<'a>,
+    path: &mut Vec<&'a str>,
+    export: &Export<'_, 'a>,
+) -> Map<String, Value> {
+    let options = export.options;
+    let mut config = Map::new();
+    path.push(state.id);
+
+    if let Some(id) = state.explicit_id() {
+        config.insert("id".to_string(), Value::from(id));
+    }
+
+    match state.typ {
+        StateType::ParallelState => {
+            config.insert("type".to_string(), Value::from("parallel"));
+        }
+        StateType::FinalState => {
+            config.insert("type".to_string(), Value::from("final"));
+        }
+        StateType::CompoundState => {
+            // parallel states enter all their children, so only compound
+            // states get an initial state
+            if let Some(initial) = state.initial {
+                // initial is the name of the sub state, which is written
+                // with the `#` if it has an explicit id
+                let key = initial.strip_prefix('#').unwrap_or(initial);
+                config.insert("initial".to_string(), Value::from(key));
+            }
+        }
+        StateType::History { mode, target } => {
+            config.insert("type".to_string(), Value::from("history"));
+            let mode = match mode {
+                HistoryMode::Shallow => "shallow",
+                HistoryMode::Deep => "deep",
+            };
+            config.insert("history".to_string(), Value::from(mode));
+            if let Some(target) = target {
+                config.insert("target".to_string(), Value::from(target));
+            }
+        }
+        StateType::AtomicState => {}
+    }
+
+    if !state.entry.is_empty() {
+        config.insert("entry".to_string(), calls_config(&state.entry));
+    }
+    if !state.exit.is_empty() {
+        config.insert("exit".to_string(), calls_config(&state.exit));
+    }
+
+    // v5 has no activities. Something which runs for as long as the state is
+    // active is an invoked service there, so activities go with the other
+    // invokes, after them.
+    let mut invoke: Vec<Value> = state
+        .invoke
+        .iter()
+        .enumerate()
+        .map(|(index, service)| invoke_config(service, index, path, export))
+        .collect();
+
+    if !state.activities.is_empty() {
+        match options.version {
+            Version::V4 => {
+                config.insert("activities".to_string(), calls_config(&state.activities));
+            }
+            Version::V5 => invoke.extend(state.activities.iter().map(activity_invoke_config)),
+        }
+    }
+
+    // xstate takes a single invoke as is, and an array for more than one
+    match invoke.len() {
+        0 => {}
+        1 => {
+            config.insert("invoke".to_string(), invoke.remove(0));
+        }
+        _ => {
+            config.insert("invoke".to_string(), Value::Array(invoke));
+        }
+    }
+
+    // Group the transitions by event, keeping the order in which the events
+    // first show up. Transitions without an event name are the transient
+    // ones. xstate v5 calls them eventless transitions and keeps them under
+    // `always`, v4 keeps them in `on` under the empty event. Delayed
+    // transitions go under `after`, grouped by the delay in milliseconds.
+    // Every transition goes along with whether it re-enters its state.
+    let mut on: Vec<(&str, Vec<(&TransitionNode, bool)>)> = vec![];
+    let mut after: Vec<(u64, Vec<(&TransitionNode, bool)>)> = vec![];
+    let mut always: Vec<(&TransitionNode, bool)> = vec![];
+
+    for (index, transition) in state.on.iter().enumerate() {
+        let reenter = export.reenters(path, TransitionList::On, index, transition);
+        let item = (transition, reenter);
+
+        if let Some(delay) = transition.delay {
+            match after.iter_mut().find(|(d, _)| *d == delay) {
+                Some((_, transitions)) => transitions.push(item),
+                None => after.push((delay, vec![item])),
+            }
+        } else if transition.event.is_empty() && options.version == Version::V5 {
+            always.push(item);
+        } else if let Some((_, transitions)) = on.iter_mut().find(|(e, _)| *e == transition.event) {
+            transitions.push(item);
+        } else {
+            on.push((transition.event, vec![item]));
+        }
+    }
+
+    if !on.is_empty() {
+        let on: Map<String, Value> = on
+            .into_iter()
+            .map(|(event, transitions)| {
+                (event.to_string(), transitions_config(&transitions, options))
+            })
+            .collect();
+        config.insert("on".to_string(), Value::Object(on));
+    }
+
+    if !after.is_empty() {
+        let after: Map<String, Value> = after
+            .into_iter()
+            .map(|(delay, transitions)| {
+                (delay.to_string(), transitions_config(&transitions, options))
+            })
+            .collect();
+        config.insert("after".to_string(), Value::Object(after));
+    }
+
+    if !always.is_empty() {
+        config.insert("always".to_string(), transitions_config(&always, options));
+    }
+
+    if !state.states.is_empty() {
+        let states: Map<String, Value> = state
+            .states
+            .iter()
+            .map(|(_, sub_state)| {
+                (
+                    sub_state.key().to_string(),
+                    Value::Object(state_config(sub_state, path, export)),
+                )
+            })
+            .collect();
+        config.insert("states".to_string(), Value::Object(states));
+    }
+
+    path.pop();
+    config
+}
+
+// index is where the invoke is in StateNode::invoke, path is the path of the
+// state it's in
+fn invoke_config<'a>(
+    service: &InvokeNode,
+    index: usize,
+    path: &[&'a str],
+    export: &Export<'_, 'a>,
+) -> Value {
+    let options = export.options;
+    let mut config = Map::new();
+    config.insert("src".to_string(), Value::from(service.src));
+
+    // `done -> loading` from inside loading re-enters it, same as any other
+    // `->` would
+    let on_done: Vec<(&TransitionNode, bool)> = service
+        .on_done
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            (
+                t,
+                export.reenters(path, TransitionList::OnDone(index), i, t),
+            )
+        })
+        .collect();
+    if !on_done.is_empty() {
+        config.insert("onDone".to_string(), transitions_config(&on_done, options));
+    }
+
+    let on_error: Vec<(&TransitionNode, bool)> = service
+        .on_error
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            (
+                t,
+                export.reenters(path, TransitionList::OnError(index), i, t),
+            )
+        })
+        .collect();
+    if !on_error.is_empty() {
+        config.insert(
+            "onError".to_string(),
+            transitions_config(&on_error, options),
+        );
+    }
+
+    Value::Object(config)
+}
+
+// All the transitions for one event. xstate tries them in order, so the
+// order from the source is kept.
+fn transitions_config(transitions: &[(&TransitionNode, bool)], options: &ExportOptions) -> Value {
+    let mut configs: Vec<Map<String, Value>> = transitions
+        .iter()
+        .map(|(transition, reenter)| transition_config(transition, *reenter, options))
+        .collect();
+
+    match (options.transitions, configs.len()) {
+        // a transition with nothing but a target is just the target
+        (TransitionFormat::Compact, 1)
+            if configs[0].len() == 1 && configs[0].contains_key("target") =>
+        {
+            configs[0]["target"].clone()
+        }
+        (TransitionFormat::Compact, 1) | (TransitionFormat::Object, 1) => {
+            Value::Object(configs.remove(0))
+        }
+        _ => Value::Array(configs.into_iter().map(Value::Object).collect()),
+    }
+}
+
+fn transition_config(
+    transition: &TransitionNode,
+    reenter: bool,
+    options: &ExportOptions,
+) -> Map<String, Value> {
+    let mut config = Map::new();
+
+    // xstate takes a transition without a target as one which stays in the
+    // state and only runs its actions
+    if !transition.target.is_empty() {
+        config.insert("target".to_string(), Value::from(transition.target));
+    }
+
+    if let Some(guard) = &transition.guard {
+        let key = match options.version {
+            Version::V4 => "cond",
+            Version::V5 => "guard",
+        };
+        config.insert(key.to_string(), guard_config(guard, options));
+    }
+
+    if let Some(actions) = &transition.actions {
+        config.insert("actions".to_string(), calls_config(actions));
+    }
+
+    // `->` is always external and `->>` always internal. xstate v4 makes
+    // transitions to children (`.child`) internal and the rest external.
+    // v5 never re-enters a state unless told to, which only makes a
+    // difference for transitions to the state itself or to a state inside
+    // it. Only what xstate would get wrong on its own is written out.
+    if !transition.target.is_empty() {
+        match options.version {
+            Version::V4 if transition.internal => {
+                config.insert("internal".to_string(), Value::from(true));
+            }
+            Version::V4 if transition.target.starts_with('.') => {
+                config.insert("internal".to_string(), Value::from(false));
+            }
+            Version::V5 if reenter => {
+                config.insert("reenter".to_string(), Value::from(true));
+            }
+            _ => {}
+        }
+    }
+
+    config
+}
+
+// A named guard is just its name. The composed ones can't be written as
+// xstate's and()/or()/not() helpers in JSON, so they become a guard named
+// after the whole expression, with the expression along with it. Whoever
+// implements the guard can evaluate it from the named guards, or register
+// the expression under that name.
+fn guard_config(guard: &Guard, options: &ExportOptions) -> Value {
+    if let Guard::Named(call) = guard {
+        return call_config(call);
+    }
+
+    // v4 keeps anything extra on the guard object, v5 wants it in params
+    match options.version {
+        Version::V4 => json!({
+            "type": guard.to_string(),
+            "expression": guard_expression(guard),
+        }),
+        Version::V5 => json!({
+            "type": guard.to_string(),
+            "params": { "expression": guard_expression(guard) },
+        }),
+    }
+}
+
+// e.g. { "and": ["isValid", { "not": "isLocked" }] }
+fn guard_expression(guard: &Guard) -> Value {
+    match guard {
+        Guard::Named(call) => call_config(call),
+        Guard::Not(guard) => json!({ "not": guard_expression(guard) }),
+        Guard::And(guards) => {
+            json!({ "and": guards.iter().map(guard_expression).collect::<Vec<_>>() })
+        }
+        Guard::Or(guards) => {
+            json!({ "or": guards.iter().map(guard_expression).collect::<Vec<_>>() })
+        }
+    }
+}
+
+fn calls_config(calls: &[Call]) -> Value {
+    Value::Array(calls.iter().map(call_config).collect())
+}
+
+// `log` is just "log", `log("entered", level=2)` is
+// { "type": "log", "params": { "0": "entered", "level": 2 } }. Positional
+// arguments go in params by their index, so one implementation of the
+// action or guard can take its arguments from params either way.
+fn call_config(call: &Call) -> Value {
+    if call.args.is_empty() {
+        return Value::from(call.name);
+    }
+
+    json!({ "type": call.name, "params": params_config(&call.args) })
+}
+
+fn params_config(args: &[Argument]) -> Value {
+    let params: Map<String, Value> = args
+        .iter()
+        .enumerate()
+        .map(|(index, arg)| {
+            let key = match arg.name {
+                Some(name) => name.to_string(),
+                None => index.to_string(),
+            };
+            (key, literal_value(&arg.value))
+        })
+        .collect();
+
+    Value::Object(params)
+}
+
+fn literal_value(literal: &Literal) -> Value {
+    match *literal {
+        Literal::String(text) => Value::from(text),
+        // 2 and not 2.0, when it is a whole number
+        Literal::Number(number) if number.fract() == 0.0 && number.abs() < i64::MAX as f64 => {
+            Value::from(number as i64)
+        }
+        Literal::Number(number) => Value::from(number),
+        Literal::Bool(value) => Value::from(value),
+    }
+}
+
+// v5 invokes activities, and the arguments become the input of the service
+fn activity_invoke_config(activity: &Call) -> Value {
+    if activity.args.is_empty() {
+        return json!({ "src": activity.name });
+    }
+
+    json!({ "src": activity.name, "input": params_config(&activity.args) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    static INPUT: &str = "abc
+  def -> lmn
+  def -> pasta; isHungry
+  ast&
+    opq -> rst; ifyes > doThis > doThat
+    nestedstate1
+    nestedstate2*
+  lastState
+    -> ast; ifyes
+    -> lastState; ifno
+  done$";
+
+    #[test]
+    fn test_to_xstate() {
+        let mut parser = Parser::new();
+        let ast = parser.parse(INPUT).unwrap();
+
+        let expected = json!({
+            "id": "abc",
+            "initial": "ast",
+            "on": {
+                "def": [
+                    { "target": "lmn" },
+                    { "target": "pasta", "guard": "isHungry" }
+                ]
+            },
+            "states": {
+                "ast": {
+                    "type": "parallel",
+                    "on": {
+                        "opq": {
+                            "target": "rst",
+                            "guard": "ifyes",
+                            "actions": ["doThis", "doThat"]
+                        }
+                    },
+                    "states": {
+                        "nestedstate1": {},
+                        "nestedstate2": {}
+                    }
+                },
+                "lastState": {
+                    "always": [
+                        { "target": "ast", "guard": "ifyes" },
+                        // `->` leaves the state and enters it again
+                        { "target": "lastState", "guard": "ifno", "reenter": true }
+                    ]
+                },
+                "done": { "type": "final" }
+            }
+        });
+
+        assert_eq!(expected, to_xstate(&ast));
+    }
+
+    #[test]
+    fn test_to_xstate_v4_compact() {
+        let mut parser = Parser::new();
+        let ast = parser.parse(INPUT).unwrap();
+        let options = ExportOptions {
+            transitions: TransitionFormat::Compact,
+            version: Version::V4,
+        };
+
+        let expected = json!({
+            "id": "abc",
+            "initial": "ast",
+            "on": {
+                "def": [
+                    { "target": "lmn" },
+                    { "target": "pasta", "cond": "isHungry" }
+                ]
+            },
+            "states": {
+                "ast": {
+                    "type": "parallel",
+                    "on": {
+                        "opq": {
+                            "target": "rst",
+                            "cond": "ifyes",
+                            "actions": ["doThis", "doThat"]
+                        }
+                    },
+                    "states": {
+                        "nestedstate1": {},
+                        "nestedstate2": {}
+                    }
+                },
+                "lastState": {
+                    "on": {
+                        "": [
+                            { "target": "ast", "cond": "ifyes" },
+                            { "target": "lastState", "cond": "ifno" }
+                        ]
+                    }
+                },
+                "done": { "type": "final" }
+            }
+        });
+
+        assert_eq!(expected, to_xstate_with_options(&ast, &options));
+    }
+
+    #[test]
+    fn test_to_xstate_keeps_source_order() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse("abc\n  zebra\n  apple\n  mango\n    b\n    a")
+            .unwrap();
+        let config = to_xstate(&ast);
+
+        // serde_json::Value compares objects without looking at the order of
+        // their keys, so look at the keys themselves
+        let keys =
+            |value: &Value| -> Vec<String> { value.as_object().unwrap().keys().cloned().collect() };
+        assert_eq!(vec!["zebra", "apple", "mango"], keys(&config["states"]));
+        assert_eq!(vec!["b", "a"], keys(&config["states"]["mango"]["states"]));
+
+        let json = to_xstate_string(&ast);
+        assert!(json.find("zebra").unwrap() < json.find("apple").unwrap());
+    }
+
+    #[test]
+    fn test_to_xstate_entry_exit() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse("abc\n  loading\n    entry > startTimer > log\n    exit > stopTimer")
+            .unwrap();
+
+        assert_eq!(
+            json!({
+                "entry": ["startTimer", "log"],
+                "exit": ["stopTimer"]
+            }),
+            to_xstate(&ast)["states"]["loading"]
+        );
+    }
+
+    #[test]
+    fn test_to_xstate_invoke_and_activities() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse(
+                "abc
+  loading
+    activity > beep
+    invoke fetchUser
+      done -> success > saveUser
+      error -> failure
+  success
+    invoke logVisit
+  failure",
+            )
+            .unwrap();
+
+        let v5 = to_xstate(&ast);
+        assert_eq!(
+            json!([
+                {
+                    "src": "fetchUser",
+                    "onDone": { "target": "success", "actions": ["saveUser"] },
+                    "onError": { "target": "failure" }
+                },
+                { "src": "beep" }
+            ]),
+            v5["states"]["loading"]["invoke"]
+        );
+        assert_eq!(
+            json!({ "src": "logVisit" }),
+            v5["states"]["success"]["invoke"]
+        );
+
+        let v4 = to_xstate_with_options(
+            &ast,
+            &ExportOptions {
+                transitions: TransitionFormat::Compact,
+                version: Version::V4,
+            },
+        );
+        assert_eq!(
+            json!({
+                "activities": ["beep"],
+                "invoke": {
+                    "src": "fetchUser",
+                    "onDone": { "target": "success", "actions": ["saveUser"] },
+                    "onError": "failure"
+                }
+            }),
+            v4["states"]["loading"]
+        );
+    }
+
+    #[test]
+    fn test_to_xstate_guards() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse("form\n  submit -> .done; isValid and not (isLocked or isBusy)\n  done")
+            .unwrap();
+        let expression = json!({
+            "and": ["isValid", { "not": { "or": ["isLocked", "isBusy"] } }]
+        });
+
+        assert_eq!(
+            json!({
+                "type": "isValid and not (isLocked or isBusy)",
+                "params": { "expression": expression }
+            }),
+            to_xstate(&ast)["on"]["submit"]["guard"]
+        );
+
+        let v4 = to_xstate_with_options(
+            &ast,
+            &ExportOptions {
+                transitions: TransitionFormat::Object,
+                version: Version::V4,
+            },
+        );
+        assert_eq!(
+            json!({
+                "type": "isValid and not (isLocked or isBusy)",
+                "expression": expression
+            }),
+            v4["on"]["submit"]["cond"]
+        );
+    }
+
+    #[test]
+    fn test_to_xstate_call_arguments() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse(
+                "abc
+  entry > log(\"entered\", level=2) > track
+  activity > poll(every=1.5)
+  submit -> abc; hasRole(admin) > save(draft=false)
+  cancel -> abc; hasRole(admin) or isOwner",
+            )
+            .unwrap();
+        let config = to_xstate(&ast);
+
+        assert_eq!(
+            json!([
+                { "type": "log", "params": { "0": "entered", "level": 2 } },
+                "track"
+            ]),
+            config["entry"]
+        );
+        assert_eq!(
+            json!({
+                "target": "abc",
+                "guard": { "type": "hasRole", "params": { "0": "admin" } },
+                "actions": [{ "type": "save", "params": { "draft": false } }]
+            }),
+            config["on"]["submit"]
+        );
+        assert_eq!(
+            json!({
+                "or": [{ "type": "hasRole", "params": { "0": "admin" } }, "isOwner"]
+            }),
+            config["on"]["cancel"]["guard"]["params"]["expression"]
+        );
+        assert_eq!(
+            json!({ "src": "poll", "input": { "every": 1.5 } }),
+            config["invoke"]
+        );
+
+        let v4 = to_xstate_with_options(
+            &ast,
+            &ExportOptions {
+                transitions: TransitionFormat::Object,
+                version: Version::V4,
+            },
+        );
+        assert_eq!(
+            json!([{ "type": "poll", "params": { "every": 1.5 } }]),
+            v4["activities"]
+        );
+    }
+
+    #[test]
+    fn test_to_xstate_targetless_transitions() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse("counter\n  increment > add, log\n  reset -> counter")
+            .unwrap();
+        let options = ExportOptions {
+            transitions: TransitionFormat::Compact,
+            version: Version::V5,
+        };
+
+        assert_eq!(
+            json!({
+                "increment": { "actions": ["add", "log"] },
+                "reset": "counter"
+            }),
+            to_xstate_with_options(&ast, &options)["on"]
+        );
+    }
+
+    #[test]
+    fn test_to_xstate_internal_transitions() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse(
+                "#form
+  editing
+    edit ->> editing > clearErrors
+    reset -> editing
+    restart -> #form.editing
+    save ->> .saving
+    submit -> .saving
+    cancel ->> done
+    saving
+  done",
+            )
+            .unwrap();
+
+        assert_eq!(
+            json!({
+                "edit": { "target": "editing", "actions": ["clearErrors"] },
+                "reset": { "target": "editing", "reenter": true },
+                "restart": { "target": "#form.editing", "reenter": true },
+                "save": ".saving",
+                "submit": { "target": ".saving", "reenter": true },
+                "cancel": "done"
+            }),
+            to_xstate_with_options(
+                &ast,
+                &ExportOptions {
+                    transitions: TransitionFormat::Compact,
+                    version: Version::V5,
+                },
+            )["states"]["editing"]["on"]
+        );
+
+        assert_eq!(
+            json!({
+                "edit": { "target": "editing", "actions": ["clearErrors"], "internal": true },
+                "reset": "editing",
+                "restart": "#form.editing",
+                "save": { "target": ".saving", "internal": true },
+                "submit": { "target": ".saving", "internal": false },
+                "cancel": { "target": "done", "internal": true }
+            }),
+            to_xstate_with_options(
+                &ast,
+                &ExportOptions {
+                    transitions: TransitionFormat::Compact,
+                    version: Version::V4,
+                },
+            )["states"]["editing"]["on"]
+        );
+    }
+
+    #[test]
+    fn test_to_xstate_invoke_reenter() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse(
+                "#app
+  loading
+    invoke fetchUser
+      done -> loading
+      error ->> loading
+    retry -> loading
+  loaded",
+            )
+            .unwrap();
+
+        let config = |version| {
+            to_xstate_with_options(
+                &ast,
+                &ExportOptions {
+                    transitions: TransitionFormat::Compact,
+                    version,
+                },
+            )["states"]["loading"]
+                .clone()
+        };
+
+        // `done ->` means the same as every other `->`
+        let v5 = config(Version::V5);
+        assert_eq!(
+            json!({ "target": "loading", "reenter": true }),
+            v5["invoke"]["onDone"]
+        );
+        assert_eq!(json!("loading"), v5["invoke"]["onError"]);
+        assert_eq!(
+            json!({ "target": "loading", "reenter": true }),
+            v5["on"]["retry"]
+        );
+
+        let v4 = config(Version::V4);
+        assert_eq!(json!("loading"), v4["invoke"]["onDone"]);
+        assert_eq!(
+            json!({ "target": "loading", "internal": true }),
+            v4["invoke"]["onError"]
+        );
+        assert_eq!(json!("loading"), v4["on"]["retry"]);
+    }
+
+    #[test]
+    fn test_to_xstate_after() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse(
+                "session
+  active
+    click -> active
+    after 5s -> warning; stillIdle
+    after 5000ms -> timeout
+    after 1.5s -> active > ping
+  warning
+  timeout$",
+            )
+            .unwrap();
+        let active = &to_xstate(&ast)["states"]["active"];
+
+        assert_eq!(
+            json!({ "click": { "target": "active", "reenter": true } }),
+            active["on"]
+        );
+        assert_eq!(
+            json!({
+                "5000": [
+                    { "target": "warning", "guard": "stillIdle" },
+                    { "target": "timeout" }
+                ],
+                "1500": { "target": "active", "actions": ["ping"], "reenter": true }
+            }),
+            active["after"]
+        );
+        let delays: Vec<&String> = active["after"].as_object().unwrap().keys().collect();
+        assert_eq!(vec!["5000", "1500"], delays);
+    }
+
+    #[test]
+    fn test_to_xstate_history() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse("wizard\n  step1\n  step2\n  resume H -> step2\n  resumeAll H*")
+            .unwrap();
+        let config = to_xstate(&ast);
+
+        assert_eq!(
+            json!({ "type": "history", "history": "shallow", "target": "step2" }),
+            config["states"]["resume"]
+        );
+        assert_eq!(
+            json!({ "type": "history", "history": "deep" }),
+            config["states"]["resumeAll"]
+        );
+    }
+
+    #[test]
+    fn test_to_xstate_explicit_ids() {
+        let mut parser = Parser::new();
+        let ast = parser
+            .parse("#machine\n  #form\n    go -> #done\n  #done$")
+            .unwrap();
+
+        let expected = json!({
+            "id": "machine",
+            "initial": "form",
+            "states": {
+                "form": {
+                    "id": "form",
+                    "on": { "go": { "target": "#done" } }
+                },
+                "done": { "id": "done", "type": "final" }
+            }
+        });
+
+        assert_eq!(expected, to_xstate(&ast));
+    }
+
+    #[test]
+    fn test_to_xstate_transition_formats() {
+        let mut parser = Parser::new();
+        let ast = parser.parse("abc\n  click -> def\n  def").unwrap();
+
+        let on = |transitions| {
+            let options = ExportOptions {
+                transitions,
+                version: Version::V5,
+            };
+            to_xstate_with_options(&ast, &options)["on"].clone()
+        };
+
+        assert_eq!(json!({ "click": "def" }), on(TransitionFormat::Compact));
+        assert_eq!(
+            json!({ "click": { "target": "def" } }),
+            on(TransitionFormat::Object)
+        );
+        assert_eq!(
+            json!({ "click": [{ "target": "def" }] }),
+            on(TransitionFormat::Array)
+        );
+    }
+}